@@ -126,6 +126,16 @@ define_barrier!(
     dmb
 );
 
+#[inline(always)]
+/// Instruction Synchronization Barrier
+///
+/// An ISB flushes the pipeline, so that instructions after it are fetched again once
+/// it completes, and see the effects of any context-changing operations before it,
+/// like writes to system registers.
+pub fn isb() { unsafe {
+    asm!("isb", options(nostack));
+} }
+
 #[inline(always)]
 /// Call `func` until it returns `true`, blocking for `wait` cycles
 /// between each try.
@@ -141,6 +151,35 @@ where
     }
 }
 
+/// a field of the calling core's `PerCpu`, through `TPIDR_EL1`. nested fields work
+/// too, so `percpu!(counters.irqs)` is a `&Cell<u64>`.
+#[macro_export]
+macro_rules! percpu {
+    ($($field:ident).+) => {
+        &$crate::percpu::this().$($field).+
+    };
+}
+
+/// the size of the smallest data cache line on this core, in bytes, from `CTR_EL0`.
+pub fn dcache_line_size() -> u64 {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack)); }
+    // `DminLine` is the log2 of the line size in words
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// clean the data cache lines covering `size` bytes at `addr` to the point of
+/// coherency, so that a core running with its mmu or caches off sees what we wrote.
+pub fn clean_dcache_range(addr: u64, size: u64) {
+    let line = dcache_line_size();
+    let mut ptr = addr & !(line - 1);
+    while ptr < addr + size {
+        unsafe { asm!("dc cvac, {}", in(reg) ptr, options(nostack)); }
+        ptr += line;
+    }
+    dsb::sy();
+}
+
 #[inline(always)]
 pub fn get_pc() -> u64 {
     let pc: u64;
@@ -1,6 +1,17 @@
 use spin::Mutex;
-use crate::driver::uart::Pl011;
+use crate::driver::uart::{Framing, Pl011};
+use crate::memory;
+
+/// the firmware sets the pl011's reference clock to 48 MHz, unless `config.txt` says
+/// otherwise with `init_uart_clock`.
+const UART_CLOCK: u32 = 48_000_000;
 
 pub static CONSOLE: Mutex<Pl011> = Mutex::new(
-    unsafe { Pl011::new(0x3F20_1000usize as *mut u8) }
+    unsafe { Pl011::new(memory::mmio(0x3F20_1000)) }
 );
+
+pub fn init() {
+    CONSOLE.lock()
+        .init(UART_CLOCK, 115_200, Framing::EIGHT_N_ONE)
+        .expect("couldn't set up the console");
+}
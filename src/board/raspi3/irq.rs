@@ -0,0 +1,22 @@
+use crate::driver::irqchip::{bcm2836, Bcm2836};
+use crate::fdt::DeviceTree;
+use crate::irq::{InterruptController, Irq};
+use crate::memory;
+use spin::Mutex;
+
+/// the el1 physical timer is the non-secure physical timer, bit 1 of each core's irq
+/// source register.
+pub const TIMER_IRQ: Irq = bcm2836::FIRST_LOCAL + 1;
+
+/// the pl011 console is gpu interrupt 57.
+pub const UART_IRQ: Irq = bcm2836::FIRST_ARMCTRL + 57;
+
+static CONTROLLER: Mutex<Bcm2836> = Mutex::new(unsafe {
+    Bcm2836::new(memory::mmio(0x4000_0000), memory::mmio(0x3F00_B200))
+});
+
+/// the arm-local controller, with `armctrl` chained off it. the device tree would
+/// only tell us where they are, which never changes on a pi 3.
+pub fn controller(_tree: Option<&DeviceTree>) -> Option<&'static Mutex<dyn InterruptController>> {
+    Some(&CONTROLLER)
+}
@@ -1 +1,18 @@
-pub const MEM_SIZE: u64 = crate::memory::GIGABYTE;
+/// `(base, size)` of each ram region, for when there's no device tree.
+pub const RAM: &[(u64, u64)] = &[(0, crate::memory::GIGABYTE)];
+
+/// `(base, size)` of each region of ram the frame allocator must leave alone.
+pub const RESERVED: &[(u64, u64)] = &[
+    // the armstub, including the spin tables the secondary cores wait on
+    (0, 0x1000),
+    // the videocore's carve-out at the top of the first gigabyte, assuming the
+    // firmware's default `gpu_mem` of 64 MiB
+    (0x3c00_0000, 0x0400_0000),
+];
+
+/// `(base, size)` of each mmio region we map: the videocore's peripherals, and the
+/// arm-local block with the per-core interrupt controller.
+pub const DEVICES: &[(u64, u64)] = &[
+    (0x3f00_0000, 0x0100_0000),
+    (0x4000_0000, 0x0004_0000),
+];
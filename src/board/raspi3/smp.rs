@@ -0,0 +1,14 @@
+use crate::psci::Conduit;
+use crate::smp::EnableMethod;
+
+/// the raspberry pi firmware doesn't implement psci, but something has to go here.
+pub const PSCI_CONDUIT: Conduit = Conduit::Smc;
+
+/// the mpidr and enable method of each core, for when there's no device tree. the
+/// armstub parks cores 1-3 on the spin table at `0xd8`, one word per core.
+pub const CPUS: &[(u64, EnableMethod)] = &[
+    (0, EnableMethod::SpinTable { release_addr: 0xd8 }),
+    (1, EnableMethod::SpinTable { release_addr: 0xe0 }),
+    (2, EnableMethod::SpinTable { release_addr: 0xe8 }),
+    (3, EnableMethod::SpinTable { release_addr: 0xf0 }),
+];
@@ -1,6 +1,21 @@
 use spin::Mutex;
-use crate::driver::uart::Pc16550d;
+use crate::driver::uart::{Framing, Pc16550d};
+use crate::memory;
+
+/// the rk3399's uarts run off a 24 MHz clock, which divides down to 1.5 Mbaud exactly.
+const UART_CLOCK: u32 = 24_000_000;
+
+/// what rockchip's own bootloaders use, and so what most people's serial consoles are
+/// already set to.
+const BAUD: u32 = 1_500_000;
 
 pub static CONSOLE: Mutex<Pc16550d> = Mutex::new(unsafe {
-    Pc16550d::new(0xff1a_0000usize as _)
+    Pc16550d::new(memory::mmio(0xff1a_0000))
 });
+
+/// whatever the bootloader left the uart at, set it to what we want.
+pub fn init() {
+    CONSOLE.lock()
+        .init(UART_CLOCK, BAUD, Framing::EIGHT_N_ONE)
+        .expect("couldn't set up the console");
+}
@@ -0,0 +1,31 @@
+use crate::driver::irqchip::GicV3;
+use crate::fdt::DeviceTree;
+use crate::irq::{InterruptController, Irq};
+use crate::memory;
+use spin::{Mutex, Once};
+
+/// the el1 physical timer is ppi 14.
+pub const TIMER_IRQ: Irq = 30;
+
+/// the console, uart2, is spi 100.
+pub const UART_IRQ: Irq = 132;
+
+/// where the rk3399's gic-500 lives, for when there's no device tree. there's one
+/// redistributor for each of the six cores.
+const GICD_BASE: u64 = 0xfee0_0000;
+const GICR_BASE: u64 = 0xfef0_0000;
+const GICR_SIZE: u64 = 0x000c_0000;
+
+static GIC: Once<Mutex<GicV3>> = Once::new();
+
+pub fn controller(tree: Option<&DeviceTree>) -> Option<&'static Mutex<dyn InterruptController>> {
+    let gic = tree.and_then(|tree| unsafe { GicV3::from_device_tree(tree) });
+    Some(GIC.call_once(|| {
+        let gic = gic.unwrap_or_else(|| unsafe {
+            let mut gic = GicV3::new(memory::mmio(GICD_BASE));
+            gic.add_redistributor_region(GICR_BASE, GICR_SIZE);
+            gic
+        });
+        Mutex::new(gic)
+    }))
+}
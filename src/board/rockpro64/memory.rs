@@ -1 +1,13 @@
-pub const MEM_SIZE: u64 = 4 * crate::memory::GIGABYTE;
+/// `(base, size)` of each ram region, for when there's no device tree. the rk3399 can
+/// address 4 GiB of dram, but the top 128 MiB of the address space is mmio.
+pub const RAM: &[(u64, u64)] = &[(0, 0xf800_0000)];
+
+/// `(base, size)` of each region of ram the frame allocator must leave alone.
+pub const RESERVED: &[(u64, u64)] = &[
+    // trusted firmware's bl31, which u-boot loads below the kernel
+    (0, 0x0020_0000),
+];
+
+/// `(base, size)` of each mmio region we map: the top 128 MiB of the 32-bit address
+/// space, which has all the rk3399's peripherals and the gic.
+pub const DEVICES: &[(u64, u64)] = &[(0xf800_0000, 0x0800_0000)];
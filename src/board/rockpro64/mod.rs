@@ -1,2 +1,4 @@
 pub mod console;
+pub mod irq;
 pub mod memory;
+pub mod smp;
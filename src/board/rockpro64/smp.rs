@@ -0,0 +1,16 @@
+use crate::psci::Conduit;
+use crate::smp::EnableMethod;
+
+/// trusted firmware implements psci at el3.
+pub const PSCI_CONDUIT: Conduit = Conduit::Smc;
+
+/// the mpidr and enable method of each core, for when there's no device tree: the four
+/// cortex-a53s in cluster 0, then the two cortex-a72s in cluster 1.
+pub const CPUS: &[(u64, EnableMethod)] = &[
+    (0x000, EnableMethod::Psci),
+    (0x001, EnableMethod::Psci),
+    (0x002, EnableMethod::Psci),
+    (0x003, EnableMethod::Psci),
+    (0x100, EnableMethod::Psci),
+    (0x101, EnableMethod::Psci),
+];
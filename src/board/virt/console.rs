@@ -1,6 +1,16 @@
 use spin::Mutex;
-use crate::driver::uart::Pl011;
+use crate::driver::uart::{Framing, Pl011};
+use crate::memory;
+
+/// qemu feeds the pl011 a 24 MHz reference clock.
+const UART_CLOCK: u32 = 24_000_000;
 
 pub static CONSOLE: Mutex<Pl011> = Mutex::new(
-    unsafe { Pl011::new(0x0900_0000 as _) }
+    unsafe { Pl011::new(memory::mmio(0x0900_0000)) }
 );
+
+pub fn init() {
+    CONSOLE.lock()
+        .init(UART_CLOCK, 115_200, Framing::EIGHT_N_ONE)
+        .expect("couldn't set up the console");
+}
@@ -0,0 +1,52 @@
+use crate::driver::irqchip::{GicV2, GicV3};
+use crate::fdt::DeviceTree;
+use crate::irq::{InterruptController, Irq};
+use crate::memory;
+use spin::{Mutex, Once};
+
+/// the el1 physical timer is ppi 14.
+pub const TIMER_IRQ: Irq = 30;
+
+/// the pl011 console is spi 1.
+pub const UART_IRQ: Irq = 33;
+
+/// where qemu puts the gic, for when there's no device tree. the distributor is in
+/// the same place whichever version we're given.
+const GICD_BASE: u64 = 0x0800_0000;
+const GICC_BASE: u64 = 0x0801_0000;
+const GICR_BASE: u64 = 0x080a_0000;
+const GICR_SIZE: u64 = 0x00f6_0000;
+
+/// true if this cpu has a gicv3 system register interface, which it won't if it's
+/// hooked up to a gicv2.
+fn has_gicv3_system_registers() -> bool {
+    let pfr0: u64;
+    unsafe { asm!("mrs {}, id_aa64pfr0_el1", out(reg) pfr0, options(nomem, nostack)) };
+    (pfr0 >> 24) & 0xf != 0
+}
+
+static GICV2: Once<Mutex<GicV2>> = Once::new();
+static GICV3: Once<Mutex<GicV3>> = Once::new();
+
+/// the interrupt controller the device tree describes. without a device tree, we
+/// guess which gic qemu gave us by whether the cpu has a gicv3 system register
+/// interface.
+pub fn controller(tree: Option<&DeviceTree>) -> Option<&'static Mutex<dyn InterruptController>> {
+    if let Some(tree) = tree {
+        if let Some(gic) = unsafe { GicV2::from_device_tree(tree) } {
+            Some(GICV2.call_once(|| Mutex::new(gic)))
+        } else if let Some(gic) = unsafe { GicV3::from_device_tree(tree) } {
+            Some(GICV3.call_once(|| Mutex::new(gic)))
+        } else {
+            None
+        }
+    } else if has_gicv3_system_registers() {
+        Some(GICV3.call_once(|| {
+            let mut gic = unsafe { GicV3::new(memory::mmio(GICD_BASE)) };
+            unsafe { gic.add_redistributor_region(GICR_BASE, GICR_SIZE) };
+            Mutex::new(gic)
+        }))
+    } else {
+        Some(GICV2.call_once(|| Mutex::new(unsafe { GicV2::new(memory::mmio(GICD_BASE), memory::mmio(GICC_BASE)) })))
+    }
+}
@@ -1 +1,11 @@
-pub const MEM_SIZE: u64 = crate::memory::GIGABYTE;
+/// `(base, size)` of each ram region, for when there's no device tree. qemu's default
+/// `-m` is 128 MiB, but our Makefile asks for a gigabyte.
+pub const RAM: &[(u64, u64)] = &[(0x4000_0000, crate::memory::GIGABYTE)];
+
+/// `(base, size)` of each region of ram the frame allocator must leave alone. qemu
+/// describes everything it cares about in the device tree.
+pub const RESERVED: &[(u64, u64)] = &[];
+
+/// `(base, size)` of each mmio region we map. this covers the gic, the pl011 and the
+/// rest of qemu's platform devices.
+pub const DEVICES: &[(u64, u64)] = &[(0x0800_0000, 0x0800_0000)];
@@ -0,0 +1,14 @@
+use crate::psci::Conduit;
+use crate::smp::EnableMethod;
+
+/// qemu implements psci itself, through `hvc` unless it's emulating el2, in which case
+/// the device tree will say `smc` instead.
+pub const PSCI_CONDUIT: Conduit = Conduit::Hvc;
+
+/// the mpidr and enable method of each core, for when there's no device tree.
+pub const CPUS: &[(u64, EnableMethod)] = &[
+    (0, EnableMethod::Psci),
+    (1, EnableMethod::Psci),
+    (2, EnableMethod::Psci),
+    (3, EnableMethod::Psci),
+];
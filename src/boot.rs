@@ -1,10 +1,13 @@
-use crate::{console, core_0_main, memory, sleep_forever};
+use crate::{board, console, core_0_main, exception, fdt, irq, memory, percpu, println, sleep_forever, smp, time};
 
 #[link_section = ".text.boot.el2_entry"]
 #[no_mangle]
 #[naked]
 pub unsafe extern "C" fn _el2_entry() -> ! {
     asm!(
+        // x0 holds the physical address of the device tree blob. stash it in x19, which
+        // nothing on the way to `init_and_enter` touches.
+        "mov x19, x0",
         // if this core0, go through the initialization routine. if
         // it's another core, sleep_forever.
         "mrs x8, mpidr_el1",
@@ -51,6 +54,22 @@ unsafe extern "C" fn become_el1(_entry: extern "C" fn() -> !) ->! {
 unsafe extern "C" fn el2_lower_to_el1(_entry: extern "C" fn() -> !) -> ! {
     // arg is in x0
     asm!(
+        // if there's a gicv3 system register interface, let el1 use it rather than
+        // trapping to us. `S3_4_C12_C9_5` is icc_sre_el2; we set its sre and enable bits.
+        "mrs x8, id_aa64pfr0_el1",
+        "ubfx x8, x8, #24, #4",
+        "cbz x8, 1f",
+        "mrs x8, S3_4_C12_C9_5",
+        "orr x8, x8, #0x1",
+        "orr x8, x8, #0x8",
+        "msr S3_4_C12_C9_5, x8",
+        "isb",
+        "1:",
+        // let el1 at the physical counter and timer (el1pcten and el1pcen), and have the
+        // virtual counter agree with the physical one
+        "mov x8, #0x3",
+        "msr cnthctl_el2, x8",
+        "msr cntvoff_el2, xzr",
         // set hcr_el2 so that el1 runs in aarch64 mode
         "mov w8, #-0x80000000",
         "msr hcr_el2, x8",
@@ -72,23 +91,157 @@ unsafe extern "C" fn el1_entry() -> ! {
         // use sp_elx at elx
         "msr spsel, #1",
 
-        // set the stack pointer to just before the beginning of the code section
+        // set the stack pointer to just before the beginning of the code section. we're
+        // still running at the physical address, so `adr` gives the physical one
         "adr x9, {text_start}",
         "mov sp, x9",
-        "b {init_and_enter}",
-        
-        text_start = sym memory::__text_start,
 
+        // turn the mmu on with the boot map, which maps us at both addresses
+        "bl {build_boot_map}",
+        "bl {enable_mmu}",
+
+        // move the stack and ourselves to the high half. 0xffff << 48 is
+        // `memory::KADDR_MIN`
+        "mov x9, #0xffff000000000000",
+        "add sp, sp, x9",
+        "adrp x10, {init_and_enter}",
+        "add x10, x10, :lo12:{init_and_enter}",
+        "add x10, x10, x9",
+        // pass along the dtb pointer stashed by `_el2_entry`
+        "mov x0, x19",
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "br x10",
+
+        text_start = sym memory::__text_start,
+        build_boot_map = sym memory::paging::build_boot_map,
+        enable_mmu = sym memory::paging::enable_mmu,
         init_and_enter = sym init_and_enter,
-        
+
         options(noreturn),
     )
 }
 
-#[link_section = ".text.boot"]
-unsafe extern "C" fn init_and_enter() -> ! {
+/// where the boot core lands, in the high half with the boot map.
+unsafe extern "C" fn init_and_enter(dtb: u64) -> ! {
     memory::init_data();
+    percpu::init(0);
+    exception::init();
     console::init_console();
-    memory::framealloc::init_frame_allocator(memory::kernel_end(), memory::max_phys_addr());
+    match fdt::init(memory::Paddr::from(dtb)) {
+        Ok(tree) => {
+            println!("device tree at {:x}, {} bytes", tree.paddr(), tree.total_size());
+        }
+        Err(e) => {
+            println!("not using a device tree: {}", e);
+        }
+    }
+    memory::physmap::init(fdt::device_tree());
+    memory::reserve_boot_regions(fdt::device_tree());
+    memory::framealloc::init_frame_allocator();
+    memory::paging::init();
+    match board::irq::controller(fdt::device_tree()) {
+        Some(controller) => {
+            irq::init(controller);
+            console::enable_interrupts();
+            irq::enable_local();
+        }
+        None => {
+            println!("no interrupt controller; leaving irqs masked");
+        }
+    }
+    time::init();
     core_0_main()
 }
+
+// the search loop in `_secondary_entry` hardcodes the length of `CORE_MPIDRS`
+const _: () = assert!(smp::MAX_CORES == 8);
+
+#[link_section = ".text.boot"]
+#[no_mangle]
+#[naked]
+/// where secondary cores start, whether released by psci or from a spin table.
+pub unsafe extern "C" fn _secondary_entry() -> ! {
+    asm!(
+        // find our index in `CORE_MPIDRS`, and keep it in x19 until `secondary_el1_entry`
+        "mrs x8, mpidr_el1",
+        // mask off everything but the affinity fields, `smp::MPIDR_AFFINITY_MASK`
+        "mov x9, #0xffff",
+        "movk x9, #0xff, lsl #16",
+        "movk x9, #0xff, lsl #32",
+        "and x8, x8, x9",
+        "adrp x9, {mpidrs}",
+        "add x9, x9, :lo12:{mpidrs}",
+        "mov x19, #0",
+        "1:",
+        "ldr x10, [x9, x19, lsl #3]",
+        "cmp x10, x8",
+        "b.eq 2f",
+        "add x19, x19, #1",
+        "cmp x19, #8",
+        "b.lo 1b",
+        // nobody asked for us. this shouldn't happen, but if it does, stay out of the way.
+        "b {sleep_forever}",
+        "2:",
+        "adr x0, {el1_entry}",
+        "b {become_el1}",
+
+        mpidrs = sym smp::CORE_MPIDRS,
+        sleep_forever = sym sleep_forever,
+        el1_entry = sym secondary_el1_entry,
+        become_el1 = sym become_el1,
+
+        options(noreturn),
+    )
+}
+
+#[link_section = ".text.boot"]
+#[naked]
+unsafe extern "C" fn secondary_el1_entry() -> ! {
+    asm!(
+        "msr spsel, #1",
+
+        // turn the mmu on with the kernel's tables, and the identity map of the image
+        // that we're running from. that doesn't need a stack
+        "adrp x0, {mmu_config}",
+        "add x0, x0, :lo12:{mmu_config}",
+        "bl {enable_mmu}",
+
+        // our core index is in x19; our stack pointer is `SECONDARY_STACKS[x19]`, which
+        // is already a high half address
+        "adrp x9, {stacks}",
+        "add x9, x9, :lo12:{stacks}",
+        "ldr x9, [x9, x19, lsl #3]",
+        "mov sp, x9",
+        // carry on at the high alias of `secondary_init_and_enter`. 0xffff << 48 is
+        // `memory::KADDR_MIN`
+        "adrp x10, {init_and_enter}",
+        "add x10, x10, :lo12:{init_and_enter}",
+        "mov x11, #0xffff000000000000",
+        "add x10, x10, x11",
+        "mov x0, x19",
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "br x10",
+
+        mmu_config = sym memory::paging::KERNEL_MMU_CONFIG,
+        enable_mmu = sym memory::paging::enable_mmu,
+        stacks = sym smp::SECONDARY_STACKS,
+        init_and_enter = sym secondary_init_and_enter,
+
+        options(noreturn),
+    )
+}
+
+/// where each secondary core lands, in the high half with the mmu on.
+unsafe extern "C" fn secondary_init_and_enter(core: usize) -> ! {
+    memory::paging::disable_identity_map();
+    percpu::init(core);
+    exception::init();
+    if irq::is_initialized() {
+        irq::init_cpu();
+        irq::enable_local();
+    }
+    time::init_cpu();
+    smp::secondary_main()
+}
@@ -1,50 +1,238 @@
 use spin::MutexGuard;
-use crate::asm::block_until;
+use crate::time::{self, Instant, TimedOut, Wait};
+use crate::{board, irq};
 use core::fmt;
 use core::ops::DerefMut;
+use core::time::Duration;
 
 pub use crate::board::console::CONSOLE;
 
+/// how long a write waits for the uart to take any of it before deciding the uart is
+/// wedged and giving up.
+pub const WEDGED_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// there's no room to write a byte right now.
+pub struct WouldBlock;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// something went wrong on the wire while receiving a byte.
+pub enum LineError {
+    /// the line was held low for longer than a whole character. there's no byte.
+    Break,
+    /// this byte didn't end with a stop bit, so it's probably garbage.
+    Framing(u8),
+    /// this byte failed its parity check.
+    Parity(u8),
+    /// this byte is fine, but the receiver ran out of room before it arrived, so some
+    /// bytes before it are gone.
+    Overrun(u8),
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LineError::Break => write!(f, "break"),
+            LineError::Framing(byte) => write!(f, "framing error (received {:#04x})", byte),
+            LineError::Parity(byte) => write!(f, "parity error (received {:#04x})", byte),
+            LineError::Overrun(_) => write!(f, "overrun; input was lost"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+/// how many of each `LineError` a console has seen, plus bytes it had to drop itself.
+pub struct LineErrorCounts {
+    pub breaks: u64,
+    pub framing: u64,
+    pub parity: u64,
+    pub overruns: u64,
+    /// bytes which arrived with nowhere to put them in the driver's own buffer.
+    pub dropped: u64,
+}
+
+impl LineErrorCounts {
+    pub const fn new() -> Self {
+        LineErrorCounts {
+            breaks: 0,
+            framing: 0,
+            parity: 0,
+            overruns: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn record(&mut self, error: LineError) {
+        let counter = match error {
+            LineError::Break => &mut self.breaks,
+            LineError::Framing(_) => &mut self.framing,
+            LineError::Parity(_) => &mut self.parity,
+            LineError::Overrun(_) => &mut self.overruns,
+        };
+        *counter += 1;
+    }
+}
+
+impl fmt::Display for LineErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} breaks, {} framing errors, {} parity errors, {} overruns, {} dropped",
+            self.breaks, self.framing, self.parity, self.overruns, self.dropped,
+        )
+    }
+}
+
 pub trait Console {
     /// write `byte` to `self` without first verifying that `self` is ready to recieve a
     /// byte.
     unsafe fn unchecked_write_byte(&mut self, byte: u8);
     fn can_write(&mut self) -> bool;
-    fn blocking_write_byte(&mut self, byte: u8) {
-        block_until(|| self.can_write(), 1);
+    /// write `byte`, giving up if there's no room for it within `WEDGED_TIMEOUT`.
+    fn blocking_write_byte(&mut self, byte: u8) -> Result<(), TimedOut> {
+        time::block_for(|| self.can_write(), WEDGED_TIMEOUT, Wait::Spin)?;
         unsafe { self.unchecked_write_byte(byte); }
+        Ok(())
+    }
+    fn try_write_byte(&mut self, byte: u8) -> Result<(), WouldBlock> {
+        if self.can_write() {
+            unsafe { self.unchecked_write_byte(byte); }
+            Ok(())
+        } else {
+            Err(WouldBlock)
+        }
+    }
+    /// write as much of `buf` as there's room for right now, returning how much that
+    /// was. drivers should override this to fill their fifo in one go.
+    fn write(&mut self, buf: &[u8]) -> usize {
+        let mut written = 0;
+        for &byte in buf {
+            if self.try_write_byte(byte).is_err() {
+                break;
+            }
+            written += 1;
+        }
+        written
+    }
+    /// write all of `buf`, unless `deadline` passes first. returns how much got written.
+    fn write_until(&mut self, buf: &[u8], deadline: Instant) -> usize {
+        let mut written = 0;
+        let _ = time::block_until(
+            || {
+                written += self.write(&buf[written..]);
+                written == buf.len()
+            },
+            deadline,
+            Wait::Spin,
+        );
+        written
     }
-    fn write_str(&mut self, s: &str) {
-        for b in s.bytes() {
-            self.blocking_write_byte(b);
+    /// write all of `s`, giving up if the uart goes `WEDGED_TIMEOUT` without taking any
+    /// of it.
+    fn write_str(&mut self, s: &str) -> Result<(), TimedOut> {
+        let mut rest = s.as_bytes();
+        while !rest.is_empty() {
+            time::block_for(|| self.can_write(), WEDGED_TIMEOUT, Wait::Spin)?;
+            rest = &rest[self.write(rest)..];
         }
+        Ok(())
     }
-    unsafe fn unchecked_read_byte(&mut self) -> u8;
+    /// read a byte from `self` without first verifying that `self` has one. a byte
+    /// which arrived damaged comes back as an error, which has already been counted.
+    unsafe fn unchecked_read_byte(&mut self) -> Result<u8, LineError>;
     fn can_read(&mut self) -> bool;
-    fn blocking_read_byte(&mut self) -> u8 {
-        block_until(|| self.can_read(), 1);
+    fn blocking_read_byte(&mut self) -> Result<u8, LineError> {
+        // can't time out
+        let _ = time::block_until(|| self.can_read(), Instant::FOREVER, Wait::Spin);
         unsafe { self.unchecked_read_byte() }
     }
+    /// a byte, if one has arrived.
+    fn try_read_byte(&mut self) -> Option<Result<u8, LineError>> {
+        if self.can_read() {
+            Some(unsafe { self.unchecked_read_byte() })
+        } else {
+            None
+        }
+    }
+    /// read whatever has already arrived, up to `buf.len()` bytes, returning how many
+    /// that was. bytes which arrived damaged are left out; `line_errors` still counts
+    /// them.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.try_read_byte() {
+                Some(Ok(byte)) | Some(Err(LineError::Overrun(byte))) => {
+                    buf[read] = byte;
+                    read += 1;
+                }
+                Some(Err(_)) => {}
+                None => break,
+            }
+        }
+        read
+    }
+    /// read until `buf` is full, unless `deadline` passes first. returns how many bytes
+    /// that was.
+    fn read_until(&mut self, buf: &mut [u8], deadline: Instant) -> usize {
+        let mut read = 0;
+        let _ = time::block_until(
+            || {
+                read += self.read(&mut buf[read..]);
+                read == buf.len()
+            },
+            deadline,
+            Wait::Spin,
+        );
+        read
+    }
+    /// every line error `self` has seen so far.
+    fn line_errors(&self) -> LineErrorCounts;
+    /// switch from polling to buffering through interrupts, from now on calling
+    /// `handle_irq` whenever the device interrupts. returns false if `self` can't.
+    fn enable_interrupts(&mut self) -> bool {
+        false
+    }
+    /// flush anything waiting to be written and go back to polling, as for a panic.
+    fn disable_interrupts(&mut self) {}
+    fn handle_irq(&mut self) {}
 }
 
-struct ConsoleWriter<T>(T);
+struct ConsoleWriter<T> {
+    console: T,
+    /// how long each write may take before failing, or `None` to wait for as long as
+    /// the uart keeps taking bytes.
+    timeout: Option<Duration>,
+}
 
 impl<T, U> fmt::Write for ConsoleWriter<T>
 where T: DerefMut<Target = U>,
       U: Console,
 {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.0.write_str(s);
+        match self.timeout {
+            None => self.console.write_str(s).map_err(|_| fmt::Error)?,
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                if self.console.write_until(s.as_bytes(), deadline) < s.len() {
+                    return Err(fmt::Error);
+                }
+            }
+        }
         Ok(())
     }
 }
 
+/// the console's irq handler takes the lock too, so don't hold it with irqs unmasked.
+/// `with_console` and `with_writing` take care of that.
 pub fn lock_console() -> MutexGuard<'static, impl Console> {
     CONSOLE.lock()
 }
 
 pub fn lock_writer() -> impl fmt::Write {
-    ConsoleWriter(lock_console())
+    ConsoleWriter {
+        console: lock_console(),
+        timeout: None,
+    }
 }
 
 pub unsafe fn force_unlock_console() {
@@ -53,20 +241,71 @@ pub unsafe fn force_unlock_console() {
     }
 }
 
-pub unsafe fn init_console() {}
+/// bring the console up from cold. anything printed before this relies on the
+/// firmware having done it.
+pub unsafe fn init_console() {
+    board::console::init();
+}
+
+fn handle_console_irq(_irq: irq::Irq) {
+    with_console(|c| c.handle_irq());
+}
+
+/// make the console interrupt-driven, if it can be. needs the interrupt controller up.
+pub fn enable_interrupts() {
+    if with_console(|c| c.enable_interrupts()) {
+        irq::register_handler(board::irq::UART_IRQ, handle_console_irq);
+    }
+}
 
 pub fn with_console<F, R>(f: F) -> R
 where
     F: FnOnce(&mut dyn Console) -> R
 {
-    f(&mut *lock_console())
+    irq::without_interrupts(|| f(&mut *lock_console()))
 }
 
 pub fn with_writing<F, R>(f: F) -> Result<R, fmt::Error>
 where
     F: FnOnce(&mut dyn fmt::Write) -> Result<R, fmt::Error>,
 {
-    f(&mut lock_writer())
+    irq::without_interrupts(|| f(&mut lock_writer()))
+}
+
+/// like `with_writing`, but any write the uart doesn't take within `timeout` fails with
+/// `fmt::Error` rather than waiting forever.
+pub fn with_writing_timeout<F, R>(timeout: Duration, f: F) -> Result<R, fmt::Error>
+where
+    F: FnOnce(&mut dyn fmt::Write) -> Result<R, fmt::Error>,
+{
+    irq::without_interrupts(|| {
+        let mut writer = ConsoleWriter {
+            console: lock_console(),
+            timeout: Some(timeout),
+        };
+        f(&mut writer)
+    })
+}
+
+/// wait for a byte from the console without holding it locked, so that everyone else
+/// can still print, and without spinning, so that the core can doze until the next
+/// interrupt.
+pub fn read_byte() -> Result<u8, LineError> {
+    let mut byte = None;
+    // can't time out
+    let _ = time::block_until(
+        || {
+            byte = with_console(|c| c.try_read_byte());
+            byte.is_some()
+        },
+        Instant::FOREVER,
+        Wait::Event,
+    );
+    byte.unwrap()
+}
+
+pub fn write_byte(byte: u8) -> Result<(), TimedOut> {
+    with_console(|c| c.blocking_write_byte(byte))
 }
 
 pub fn print_str(s: &str) -> fmt::Result {
@@ -24,4 +24,6 @@ macro_rules! define_register_block {
     };
 }
 
+pub mod irqchip;
+pub mod timer;
 pub mod uart;
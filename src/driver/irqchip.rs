@@ -0,0 +1,15 @@
+#[cfg(feature = "raspi3")]
+pub mod bcm2835;
+#[cfg(feature = "raspi3")]
+pub mod bcm2836;
+#[cfg(feature = "virt")]
+pub mod gicv2;
+#[cfg(any(feature = "virt", feature = "rockpro64"))]
+pub mod gicv3;
+
+#[cfg(feature = "raspi3")]
+pub use bcm2836::Bcm2836;
+#[cfg(feature = "virt")]
+pub use gicv2::GicV2;
+#[cfg(any(feature = "virt", feature = "rockpro64"))]
+pub use gicv3::GicV3;
@@ -0,0 +1,72 @@
+//! the bcm2835 "armctrl" interrupt controller, which collects the videocore's
+//! peripheral interrupts (the uarts, the system timer, usb and so on) into one line
+//! for the arm. see chapter 7 of the bcm2835 arm peripherals manual.
+//!
+//! on the bcm2836 and later, that line goes to the arm-local controller in
+//! `bcm2836.rs`, which is the only thing that should use this directly.
+
+use tock_registers::{
+    registers::{ReadOnly, WriteOnly},
+    interfaces::{Readable, Writeable},
+};
+
+/// gpu interrupts are 0 through 63 in our numbering, and the "basic" interrupts
+/// (the arm timer, the arm mailbox, doorbells and so on) follow them.
+pub const FIRST_BASIC: u32 = 64;
+
+define_register_block! {
+    pub Armctrl {
+        0x00 => pub basic_pending: ReadOnly<u32>,
+        0x04 => pub pending: [ReadOnly<u32>; 2],
+        0x10 => pub enable: [WriteOnly<u32>; 2],
+        0x18 => pub enable_basic: WriteOnly<u32>,
+        0x1c => pub disable: [WriteOnly<u32>; 2],
+        0x24 => pub disable_basic: WriteOnly<u32>,
+    }
+}
+
+unsafe impl Send for Armctrl {}
+
+impl Armctrl {
+    /// disable everything.
+    pub fn reset(&mut self) {
+        self.disable()[0].set(!0);
+        self.disable()[1].set(!0);
+        self.disable_basic().set(!0);
+    }
+
+    pub fn enable_irq(&mut self, irq: u32) {
+        match irq {
+            0..=63 => self.enable()[irq as usize / 32].set(1 << (irq % 32)),
+            FIRST_BASIC..=71 => self.enable_basic().set(1 << (irq - FIRST_BASIC)),
+            _ => {}
+        }
+    }
+
+    pub fn disable_irq(&mut self, irq: u32) {
+        match irq {
+            0..=63 => self.disable()[irq as usize / 32].set(1 << (irq % 32)),
+            FIRST_BASIC..=71 => self.disable_basic().set(1 << (irq - FIRST_BASIC)),
+            _ => {}
+        }
+    }
+
+    /// the lowest numbered pending interrupt, if there is one.
+    ///
+    /// the basic pending register has shortcut bits for a few gpu interrupts, which
+    /// then don't show up in its "something in pending[n]" bits, so rather than
+    /// decode those we just look at both gpu pending registers.
+    pub fn next_pending(&mut self) -> Option<u32> {
+        let basic = self.basic_pending().get() & 0xff;
+        if basic != 0 {
+            return Some(FIRST_BASIC + basic.trailing_zeros());
+        }
+        for bank in 0..2 {
+            let pending = self.pending()[bank].get();
+            if pending != 0 {
+                return Some(32 * bank as u32 + pending.trailing_zeros());
+            }
+        }
+        None
+    }
+}
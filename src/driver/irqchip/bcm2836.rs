@@ -0,0 +1,217 @@
+//! the bcm2836 arm-local interrupt controller, which the bcm2837 in the raspberry pi 3
+//! keeps. it routes each core's timer and mailbox interrupts to that core, and the
+//! `armctrl` line from `bcm2835.rs` to one core of our choosing. see the "quad-a7
+//! control" document, qa7_rev3.4.
+//!
+//! there are no real sgis, so we make them out of mailbox 0: sgi `n` to a core is bit
+//! `n` of that core's mailbox 0. the numbering follows the gic's lead, so that the rest
+//! of the kernel can mostly not care:
+//!
+//! - 0..16 are sgis
+//! - 16..32 are the per-core sources, `16 + n` being bit `n` of the core's irq source
+//!   register, so the generic timers are 16..20
+//! - 32.. are the `armctrl` interrupts, offset by 32
+
+use tock_registers::{
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+    interfaces::{Readable, Writeable},
+};
+use super::bcm2835::Armctrl;
+use crate::irq::{InterruptController, Irq, SgiTarget};
+use crate::smp;
+use core::sync::atomic::Ordering;
+
+pub const NUM_CORES: usize = 4;
+
+/// where the per-core sources start.
+pub const FIRST_LOCAL: Irq = 16;
+
+/// where the `armctrl` interrupts start.
+pub const FIRST_ARMCTRL: Irq = 32;
+
+/// bits in a core's irq source register.
+const SOURCE_MAILBOX0: u32 = 1 << 4;
+const SOURCE_ARMCTRL: u32 = 1 << 8;
+
+/// per-core sources which aren't the timers or mailboxes, by their bit in the irq source
+/// register.
+const LOCAL_PMU: u32 = 9;
+const LOCAL_AXI: u32 = 10;
+const LOCAL_TIMER: u32 = 11;
+
+/// the interrupt enable bits in `axi_outstanding_irq` and `local_timer_control`.
+const AXI_IRQ_ENABLE: u32 = 1 << 20;
+const LOCAL_TIMER_IRQ_ENABLE: u32 = 1 << 29;
+
+define_register_block! {
+    pub Local {
+        // which core gets the `armctrl` line as its irq, in bits 0-1
+        0x0c => pub gpu_routing: ReadWrite<u32>,
+        // bit n sends core n's pmu interrupt to its irq
+        0x10 => pub pmu_routing_set: WriteOnly<u32>,
+        0x14 => pub pmu_routing_clear: WriteOnly<u32>,
+        // which core gets the local timer as its irq, in bits 0-2
+        0x24 => pub local_timer_routing: ReadWrite<u32>,
+        0x2c => pub axi_outstanding_irq: ReadWrite<u32>,
+        0x34 => pub local_timer_control: ReadWrite<u32>,
+        0x40 => pub timer_control: [ReadWrite<u32>; NUM_CORES],
+        0x50 => pub mailbox_control: [ReadWrite<u32>; NUM_CORES],
+        0x60 => pub irq_source: [ReadOnly<u32>; NUM_CORES],
+        0x80 => pub mailbox_set: [[WriteOnly<u32>; 4]; NUM_CORES],
+        0xc0 => pub mailbox_clear: [[ReadWrite<u32>; 4]; NUM_CORES],
+    }
+}
+
+pub struct Bcm2836 {
+    local: Local,
+    armctrl: Armctrl,
+    /// whether the first `init_cpu` has happened.
+    initialized: bool,
+}
+
+unsafe impl Send for Bcm2836 {}
+
+/// the local controller's number for the calling core.
+fn this_core() -> usize {
+    (smp::current_mpidr() & 0xff) as usize % NUM_CORES
+}
+
+/// the local controller's number for core `core`, by our numbering.
+fn hardware_core(core: usize) -> usize {
+    (smp::CORE_MPIDRS[core].load(Ordering::Acquire) & 0xff) as usize % NUM_CORES
+}
+
+impl Bcm2836 {
+    pub const unsafe fn new(local_base: *mut u8, armctrl_base: *mut u8) -> Self {
+        Bcm2836 {
+            local: Local::new(local_base),
+            armctrl: Armctrl::new(armctrl_base),
+            initialized: false,
+        }
+    }
+
+    /// flip the enable bit for per-core source `bit` on the calling core. there's only
+    /// one local timer and one axi interrupt, which go to a single core: enabling the
+    /// local timer moves it to the calling core, and the axi interrupt only ever goes to
+    /// core 0.
+    fn set_local_enable(&mut self, bit: u32, enabled: bool) {
+        let core = this_core();
+        let set = |reg: &mut ReadWrite<u32>, mask: u32| {
+            let value = if enabled { reg.get() | mask } else { reg.get() & !mask };
+            reg.set(value);
+        };
+        match bit {
+            0..=3 => set(&mut self.local.timer_control()[core], 1 << bit),
+            4..=7 => set(&mut self.local.mailbox_control()[core], 1 << (bit - 4)),
+            LOCAL_PMU if enabled => self.local.pmu_routing_set().set(1 << core),
+            LOCAL_PMU => self.local.pmu_routing_clear().set(1 << core),
+            LOCAL_AXI => set(self.local.axi_outstanding_irq(), AXI_IRQ_ENABLE),
+            LOCAL_TIMER => {
+                if enabled {
+                    self.local.local_timer_routing().set(core as u32);
+                }
+                set(self.local.local_timer_control(), LOCAL_TIMER_IRQ_ENABLE);
+            }
+            // the `armctrl` line is enabled through its own numbers, and there's nothing
+            // past the local timer
+            _ => panic!("irq {} isn't a per-core source we can mask", FIRST_LOCAL + bit),
+        }
+    }
+}
+
+impl InterruptController for Bcm2836 {
+    fn init_cpu(&mut self) {
+        let core = this_core();
+
+        if !self.initialized {
+            self.armctrl.reset();
+            self.local.gpu_routing().set(core as u32);
+            let axi = self.local.axi_outstanding_irq().get();
+            self.local.axi_outstanding_irq().set(axi & !AXI_IRQ_ENABLE);
+            let timer = self.local.local_timer_control().get();
+            self.local.local_timer_control().set(timer & !LOCAL_TIMER_IRQ_ENABLE);
+            self.initialized = true;
+        }
+
+        // sgis are always enabled; everything else waits until someone asks for it
+        self.local.timer_control()[core].set(0);
+        self.local.mailbox_clear()[core][0].set(!0);
+        self.local.mailbox_control()[core].set(1);
+        self.local.pmu_routing_clear().set(1 << core);
+    }
+
+    fn enable(&mut self, irq: Irq) {
+        match irq {
+            FIRST_LOCAL..=31 => self.set_local_enable(irq - FIRST_LOCAL, true),
+            FIRST_ARMCTRL..=Irq::MAX => self.armctrl.enable_irq(irq - FIRST_ARMCTRL),
+            _ => {}
+        }
+    }
+
+    fn disable(&mut self, irq: Irq) {
+        match irq {
+            FIRST_LOCAL..=31 => self.set_local_enable(irq - FIRST_LOCAL, false),
+            FIRST_ARMCTRL..=Irq::MAX => self.armctrl.disable_irq(irq - FIRST_ARMCTRL),
+            _ => {}
+        }
+    }
+
+    fn set_priority(&mut self, _irq: Irq, _priority: u8) {
+        // no priorities here
+    }
+
+    fn set_target(&mut self, irq: Irq, core: usize) {
+        // armctrl interrupts all go to the same core, so moving one moves them all
+        if irq >= FIRST_ARMCTRL {
+            self.local.gpu_routing().set(hardware_core(core) as u32);
+        }
+    }
+
+    fn acknowledge(&mut self) -> Option<Irq> {
+        let core = this_core();
+        let source = self.local.irq_source()[core].get();
+
+        if source & SOURCE_MAILBOX0 != 0 {
+            // claim the sgi now rather than in `end_of_interrupt`, so that another one
+            // arriving while we handle this one isn't lost
+            let mailbox = &mut self.local.mailbox_clear()[core][0];
+            let pending = mailbox.get();
+            let sgi = pending.trailing_zeros();
+            if sgi < 16 {
+                mailbox.set(1 << sgi);
+                return Some(sgi);
+            }
+            // nobody should be setting the high bits, but they'd keep the line up
+            mailbox.set(pending);
+        }
+
+        if source & SOURCE_ARMCTRL != 0 {
+            if let Some(irq) = self.armctrl.next_pending() {
+                return Some(FIRST_ARMCTRL + irq);
+            }
+        }
+
+        let local = source & !(SOURCE_MAILBOX0 | SOURCE_ARMCTRL) & 0xffff;
+        if local != 0 {
+            return Some(FIRST_LOCAL + local.trailing_zeros());
+        }
+        None
+    }
+
+    fn end_of_interrupt(&mut self, _irq: Irq) {
+        // sgis are claimed in `acknowledge`, and everything else is level-triggered and
+        // cleared at its source
+    }
+
+    fn send_sgi(&mut self, sgi: Irq, target: SgiTarget) {
+        let me = this_core();
+        for core in 0..NUM_CORES {
+            let send = match target {
+                SgiTarget::AllOthers => core != me,
+            };
+            if send {
+                self.local.mailbox_set()[core][0].set(1 << sgi);
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! the arm generic interrupt controller, version 2, as in the gic-400 on qemu's virt
+//! board. see arm ihi 0048b, the gic architecture specification.
+
+use tock_registers::{
+    register_bitfields,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+    interfaces::{Readable, Writeable},
+};
+use crate::irq::{InterruptController, Irq, SgiTarget, DEFAULT_PRIORITY};
+use crate::fdt::DeviceTree;
+use crate::memory;
+use crate::percpu;
+use crate::smp::MAX_CORES;
+
+/// what the device tree calls a gicv2.
+pub const COMPATIBLE: &[&str] = &["arm,gic-400", "arm,cortex-a15-gic", "arm,cortex-a9-gic"];
+
+/// the interrupt id the cpu interface hands out when there's nothing pending.
+const SPURIOUS: u32 = 1023;
+
+/// the most interrupt ids a gicv2 can have; 1020 through 1023 are special.
+const MAX_LINES: u32 = 1020;
+
+register_bitfields! {
+    u32,
+    /// Distributor Control Register
+    GICD_CTLR [
+        enable OFFSET(0) NUMBITS(1) []
+    ],
+    /// Interrupt Controller Type Register
+    GICD_TYPER [
+        cpu_number OFFSET(5) NUMBITS(3) [],
+        /// the gic supports 32 * (`it_lines_number` + 1) interrupt ids.
+        it_lines_number OFFSET(0) NUMBITS(5) []
+    ],
+    /// Software Generated Interrupt Register
+    GICD_SGIR [
+        target_list_filter OFFSET(24) NUMBITS(2) [
+            List = 0,
+            AllOthers = 1,
+            Myself = 2
+        ],
+        cpu_target_list OFFSET(16) NUMBITS(8) [],
+        intid OFFSET(0) NUMBITS(4) []
+    ],
+    /// CPU Interface Control Register
+    GICC_CTLR [
+        enable OFFSET(0) NUMBITS(1) []
+    ],
+    /// Interrupt Acknowledge Register, whose value also goes in the End of Interrupt
+    /// Register
+    GICC_IAR [
+        /// for an sgi, which core sent it.
+        cpuid OFFSET(10) NUMBITS(3) [],
+        intid OFFSET(0) NUMBITS(10) []
+    ]
+}
+
+define_register_block! {
+    pub Distributor {
+        0x000 => pub ctlr: ReadWrite<u32, GICD_CTLR::Register>,
+        0x004 => pub typer: ReadOnly<u32, GICD_TYPER::Register>,
+        0x100 => pub isenabler: [ReadWrite<u32>; 32],
+        0x180 => pub icenabler: [ReadWrite<u32>; 32],
+        // the priority and target registers are byte-accessible, which saves us a
+        // read-modify-write.
+        0x400 => pub ipriorityr: [ReadWrite<u8>; 1024],
+        0x800 => pub itargetsr: [ReadWrite<u8>; 1024],
+        0xc00 => pub icfgr: [ReadWrite<u32>; 64],
+        0xf00 => pub sgir: WriteOnly<u32, GICD_SGIR::Register>,
+    }
+}
+
+define_register_block! {
+    pub CpuInterface {
+        0x00 => pub ctlr: ReadWrite<u32, GICC_CTLR::Register>,
+        0x04 => pub pmr: ReadWrite<u32>,
+        0x08 => pub bpr: ReadWrite<u32>,
+        0x0c => pub iar: ReadOnly<u32, GICC_IAR::Register>,
+        0x10 => pub eoir: WriteOnly<u32, GICC_IAR::Register>,
+    }
+}
+
+pub struct GicV2 {
+    gicd: Distributor,
+    /// every core sees its own cpu interface at the same address.
+    gicc: CpuInterface,
+    /// how many interrupt ids the distributor implements. zero until the first
+    /// `init_cpu`.
+    lines: u32,
+    /// the bit in `itargetsr` for each core, indexed by core number. they're usually
+    /// `1 << core`, but nothing promises that, so each core reads its own out of the
+    /// banked `itargetsr[0]` in `init_cpu`.
+    cpu_masks: [u8; MAX_CORES],
+    /// the last value each core read from `iar`, which has to go back into `eoir`
+    /// unchanged.
+    acked: [u32; MAX_CORES],
+}
+
+unsafe impl Send for GicV2 {}
+
+impl GicV2 {
+    pub const unsafe fn new(gicd_base: *mut u8, gicc_base: *mut u8) -> Self {
+        GicV2 {
+            gicd: Distributor::new(gicd_base),
+            gicc: CpuInterface::new(gicc_base),
+            lines: 0,
+            cpu_masks: [0; MAX_CORES],
+            acked: [SPURIOUS; MAX_CORES],
+        }
+    }
+
+    /// the first gicv2 the device tree describes.
+    pub unsafe fn from_device_tree(tree: &DeviceTree) -> Option<Self> {
+        let node = tree.find_any_compatible(COMPATIBLE)?;
+        let mut reg = node.reg();
+        let gicd = reg.next()?.address;
+        let gicc = reg.next()?.address;
+        Some(GicV2::new(memory::mmio(gicd), memory::mmio(gicc)))
+    }
+
+    /// disable and reset every shared interrupt, then turn the distributor on. shared
+    /// interrupts start out routed to the boot core.
+    fn init_distributor(&mut self) {
+        self.gicd.ctlr().write(GICD_CTLR::enable::CLEAR);
+
+        let lines = 32 * (self.gicd.typer().read(GICD_TYPER::it_lines_number) + 1);
+        self.lines = lines.min(MAX_LINES);
+
+        let boot_mask = self.cpu_mask(0);
+        for reg in 1..(self.lines as usize / 32) {
+            self.gicd.icenabler()[reg].set(!0);
+        }
+        for irq in 32..(self.lines as usize) {
+            self.gicd.ipriorityr()[irq].set(DEFAULT_PRIORITY);
+            self.gicd.itargetsr()[irq].set(boot_mask);
+        }
+        // level-sensitive, which is what every device we drive wants
+        for reg in 2..(self.lines as usize / 16) {
+            self.gicd.icfgr()[reg].set(0);
+        }
+
+        self.gicd.ctlr().write(GICD_CTLR::enable::SET);
+    }
+
+    fn cpu_mask(&self, core: usize) -> u8 {
+        match self.cpu_masks[core] {
+            0 => 1 << core,
+            mask => mask,
+        }
+    }
+
+    fn in_range(&self, irq: Irq) -> bool {
+        irq < self.lines
+    }
+}
+
+impl InterruptController for GicV2 {
+    fn init_cpu(&mut self) {
+        let core = percpu::this().core_id();
+        // `itargetsr[0..8]` are banked, and read back as the reading core's own bit
+        self.cpu_masks[core] = self.gicd.itargetsr()[0].get();
+
+        if self.lines == 0 {
+            self.init_distributor();
+        }
+
+        // sgis are always enabled; ppis wait until someone asks for them
+        self.gicd.icenabler()[0].set(0xffff_0000);
+        self.gicd.isenabler()[0].set(0x0000_ffff);
+        for irq in 0..32 {
+            self.gicd.ipriorityr()[irq].set(DEFAULT_PRIORITY);
+        }
+
+        // let every priority through, and don't split priorities into groups
+        self.gicc.pmr().set(0xff);
+        self.gicc.bpr().set(0);
+        self.gicc.ctlr().write(GICC_CTLR::enable::SET);
+    }
+
+    fn enable(&mut self, irq: Irq) {
+        if self.in_range(irq) {
+            self.gicd.isenabler()[irq as usize / 32].set(1 << (irq % 32));
+        }
+    }
+
+    fn disable(&mut self, irq: Irq) {
+        if self.in_range(irq) {
+            self.gicd.icenabler()[irq as usize / 32].set(1 << (irq % 32));
+        }
+    }
+
+    fn set_priority(&mut self, irq: Irq, priority: u8) {
+        if self.in_range(irq) {
+            self.gicd.ipriorityr()[irq as usize].set(priority);
+        }
+    }
+
+    fn set_target(&mut self, irq: Irq, core: usize) {
+        // sgis and ppis always go to the core they belong to
+        if irq >= 32 && self.in_range(irq) {
+            let mask = self.cpu_mask(core);
+            self.gicd.itargetsr()[irq as usize].set(mask);
+        }
+    }
+
+    fn acknowledge(&mut self) -> Option<Irq> {
+        let iar = self.gicc.iar().get();
+        let irq = iar & 0x3ff;
+        if irq == SPURIOUS {
+            None
+        } else {
+            self.acked[percpu::this().core_id()] = iar;
+            Some(irq)
+        }
+    }
+
+    fn end_of_interrupt(&mut self, irq: Irq) {
+        let acked = self.acked[percpu::this().core_id()];
+        let iar = if acked & 0x3ff == irq { acked } else { irq };
+        self.gicc.eoir().set(iar);
+    }
+
+    fn send_sgi(&mut self, sgi: Irq, target: SgiTarget) {
+        let filter = match target {
+            SgiTarget::AllOthers => GICD_SGIR::target_list_filter::AllOthers,
+        };
+        self.gicd.sgir().write(filter + GICD_SGIR::intid.val(sgi));
+    }
+}
@@ -0,0 +1,371 @@
+//! the arm generic interrupt controller, version 3, as in qemu's virt board with
+//! `gic-version=3` and the gic-500 in the rk3399. see arm ihi 0069, the gicv3 and
+//! gicv4 architecture specification.
+//!
+//! unlike a gicv2, each core has its own redistributor, which holds the
+//! configuration of its sgis and ppis, and talks to its cpu interface through the
+//! `ICC_*_EL1` system registers. we don't do lpis.
+
+use tock_registers::{
+    register_bitfields,
+    registers::{ReadOnly, ReadWrite},
+    interfaces::{Readable, Writeable, ReadWriteable},
+};
+use crate::irq::{InterruptController, Irq, SgiTarget, DEFAULT_PRIORITY};
+use crate::smp::{self, MAX_CORES};
+use crate::fdt::DeviceTree;
+use crate::memory;
+use crate::time::{self, Wait};
+use crate::{asm, percpu};
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+/// what the device tree calls a gicv3.
+pub const COMPATIBLE: &[&str] = &["arm,gic-v3"];
+
+/// interrupt ids from here on are special, or lpis.
+const FIRST_SPECIAL: u32 = 1020;
+
+/// the most redistributor regions we keep track of.
+const MAX_REDIST_REGIONS: usize = 4;
+
+/// each redistributor has an `RD_base` frame and an `SGI_base` frame, each 64k.
+const REDIST_STRIDE: u64 = 0x2_0000;
+
+/// ... plus two more if it supports virtual lpis.
+const REDIST_STRIDE_VLPI: u64 = 0x4_0000;
+
+/// the longest we wait for a register write to take effect, or for a redistributor to
+/// wake up. it should take microseconds, so a gic that takes this long is broken.
+const GIC_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// spin until `done`, panicking if it takes longer than `GIC_TIMEOUT`. we can't carry on
+/// without the gic, so there's nothing better to do.
+fn wait_for<F: FnMut() -> bool>(what: &str, done: F) {
+    if time::block_for(done, GIC_TIMEOUT, Wait::Spin).is_err() {
+        panic!("gicv3: timed out waiting for {}", what);
+    }
+}
+
+register_bitfields! {
+    u32,
+    /// Distributor Control Register, as seen from non-secure state
+    GICD_CTLR [
+        /// register write pending
+        rwp OFFSET(31) NUMBITS(1) [],
+        /// affinity routing enable
+        are_ns OFFSET(4) NUMBITS(1) [],
+        enable_grp1 OFFSET(1) NUMBITS(1) []
+    ],
+    /// Interrupt Controller Type Register
+    GICD_TYPER [
+        /// the gic supports 32 * (`it_lines_number` + 1) interrupt ids.
+        it_lines_number OFFSET(0) NUMBITS(5) []
+    ],
+    /// Redistributor Control Register
+    GICR_CTLR [
+        /// register write pending
+        rwp OFFSET(3) NUMBITS(1) []
+    ],
+    /// Redistributor Wake Register
+    GICR_WAKER [
+        children_asleep OFFSET(2) NUMBITS(1) [],
+        processor_sleep OFFSET(1) NUMBITS(1) []
+    ]
+}
+
+register_bitfields! {
+    u64,
+    /// Redistributor Type Register
+    GICR_TYPER [
+        /// the affinity of the core this redistributor belongs to, as aff3.aff2.aff1.aff0
+        affinity OFFSET(32) NUMBITS(32) [],
+        /// this is the last redistributor in its region.
+        last OFFSET(4) NUMBITS(1) [],
+        /// this redistributor has the extra frames for virtual lpis.
+        vlpis OFFSET(1) NUMBITS(1) []
+    ]
+}
+
+define_register_block! {
+    pub Distributor {
+        0x0000 => pub ctlr: ReadWrite<u32, GICD_CTLR::Register>,
+        0x0004 => pub typer: ReadOnly<u32, GICD_TYPER::Register>,
+        0x0080 => pub igroupr: [ReadWrite<u32>; 32],
+        0x0100 => pub isenabler: [ReadWrite<u32>; 32],
+        0x0180 => pub icenabler: [ReadWrite<u32>; 32],
+        0x0400 => pub ipriorityr: [ReadWrite<u8>; 1024],
+        0x0c00 => pub icfgr: [ReadWrite<u32>; 64],
+        0x6000 => pub irouter: [ReadWrite<u64>; 1024],
+    }
+}
+
+// one core's redistributor, both its `RD_base` frame and its `SGI_base` frame.
+define_register_block! {
+    pub Redistributor {
+        0x0_0000 => pub ctlr: ReadWrite<u32, GICR_CTLR::Register>,
+        0x0_0008 => pub typer: ReadOnly<u64, GICR_TYPER::Register>,
+        0x0_0014 => pub waker: ReadWrite<u32, GICR_WAKER::Register>,
+        0x1_0080 => pub igroupr0: ReadWrite<u32>,
+        0x1_0100 => pub isenabler0: ReadWrite<u32>,
+        0x1_0180 => pub icenabler0: ReadWrite<u32>,
+        0x1_0400 => pub ipriorityr: [ReadWrite<u8>; 32],
+    }
+}
+
+/// an `ICC_*_EL1` register, by its generic encoding, since not every assembler knows
+/// their names.
+macro_rules! icc_register {
+    ($name:ident, $encoding:literal) => {
+        // not every register gets both read and written
+        #[allow(non_snake_case, dead_code)]
+        mod $name {
+            #[inline(always)]
+            pub fn get() -> u64 {
+                let value;
+                unsafe { asm!(concat!("mrs {}, ", $encoding), out(reg) value, options(nostack)) };
+                value
+            }
+            #[inline(always)]
+            pub fn set(value: u64) {
+                unsafe { asm!(concat!("msr ", $encoding, ", {}"), in(reg) value, options(nostack)) };
+            }
+        }
+    };
+}
+
+icc_register!(ICC_PMR_EL1, "S3_0_C4_C6_0");
+icc_register!(ICC_IAR1_EL1, "S3_0_C12_C12_0");
+icc_register!(ICC_EOIR1_EL1, "S3_0_C12_C12_1");
+icc_register!(ICC_BPR1_EL1, "S3_0_C12_C12_3");
+icc_register!(ICC_SRE_EL1, "S3_0_C12_C12_5");
+icc_register!(ICC_IGRPEN1_EL1, "S3_0_C12_C12_7");
+icc_register!(ICC_SGI1R_EL1, "S3_0_C12_C11_5");
+
+/// the affinity fields of `mpidr`, packed aff3.aff2.aff1.aff0 the way `GICR_TYPER`
+/// has them.
+fn packed_affinity(mpidr: u64) -> u32 {
+    ((mpidr & 0xff_ffff) | ((mpidr >> 8) & 0xff00_0000)) as u32
+}
+
+/// the affinity fields of `mpidr`, laid out the way `GICD_IROUTER` wants them.
+fn routing_affinity(mpidr: u64) -> u64 {
+    mpidr & smp::MPIDR_AFFINITY_MASK
+}
+
+pub struct GicV3 {
+    gicd: Distributor,
+    /// the base and size of each region of redistributors.
+    redist_regions: [(u64, u64); MAX_REDIST_REGIONS],
+    num_redist_regions: usize,
+    /// the base of each core's redistributor, indexed by core number, or zero if we
+    /// haven't found it yet.
+    redists: [u64; MAX_CORES],
+    /// how many interrupt ids the distributor implements. zero until the first
+    /// `init_cpu`.
+    lines: u32,
+}
+
+unsafe impl Send for GicV3 {}
+
+impl GicV3 {
+    pub const unsafe fn new(gicd_base: *mut u8) -> Self {
+        GicV3 {
+            gicd: Distributor::new(gicd_base),
+            redist_regions: [(0, 0); MAX_REDIST_REGIONS],
+            num_redist_regions: 0,
+            redists: [0; MAX_CORES],
+            lines: 0,
+        }
+    }
+
+    /// the first gicv3 the device tree describes, with all its redistributor regions.
+    pub unsafe fn from_device_tree(tree: &DeviceTree) -> Option<Self> {
+        let node = tree.find_any_compatible(COMPATIBLE)?;
+        let regions = node.property("#redistributor-regions")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(1) as usize;
+        let mut reg = node.reg();
+        let mut gic = GicV3::new(memory::mmio(reg.next()?.address));
+        for region in reg.take(regions.min(MAX_REDIST_REGIONS)) {
+            gic.add_redistributor_region(region.address, region.size);
+        }
+        Some(gic)
+    }
+
+    /// look for redistributors in the `size` bytes at `base`. has to happen before
+    /// the first `init_cpu`.
+    pub unsafe fn add_redistributor_region(&mut self, base: u64, size: u64) {
+        assert!(self.num_redist_regions < MAX_REDIST_REGIONS, "too many redistributor regions");
+        self.redist_regions[self.num_redist_regions] = (base, size);
+        self.num_redist_regions += 1;
+    }
+
+    /// walk the redistributor regions for the one whose affinity is `mpidr`.
+    fn find_redistributor(&self, mpidr: u64) -> Option<u64> {
+        let affinity = packed_affinity(mpidr);
+        for &(base, size) in &self.redist_regions[..self.num_redist_regions] {
+            let mut frame = base;
+            while frame + REDIST_STRIDE <= base + size {
+                let mut redist = unsafe { Redistributor::new(memory::mmio(frame)) };
+                let typer = redist.typer().extract();
+                if typer.read(GICR_TYPER::affinity) as u32 == affinity {
+                    return Some(frame);
+                }
+                if typer.is_set(GICR_TYPER::last) {
+                    break;
+                }
+                frame += if typer.is_set(GICR_TYPER::vlpis) {
+                    REDIST_STRIDE_VLPI
+                } else {
+                    REDIST_STRIDE
+                };
+            }
+        }
+        None
+    }
+
+    /// the calling core's redistributor.
+    fn redist(&self) -> Redistributor {
+        let base = self.redists[percpu::this().core_id()];
+        debug_assert!(base != 0, "gicv3: init_cpu wasn't called on this core");
+        unsafe { Redistributor::new(memory::mmio(base)) }
+    }
+
+    fn wait_for_distributor(&mut self) {
+        wait_for("a distributor write", || !self.gicd.ctlr().is_set(GICD_CTLR::rwp));
+    }
+
+    /// disable and reset every shared interrupt, then turn the distributor on with
+    /// affinity routing. shared interrupts start out routed to the calling core.
+    fn init_distributor(&mut self) {
+        self.gicd.ctlr().set(0);
+        self.wait_for_distributor();
+
+        let lines = 32 * (self.gicd.typer().read(GICD_TYPER::it_lines_number) + 1);
+        self.lines = lines.min(FIRST_SPECIAL);
+
+        let here = routing_affinity(smp::current_mpidr());
+        for reg in 1..(self.lines as usize / 32) {
+            self.gicd.icenabler()[reg].set(!0);
+            self.gicd.igroupr()[reg].set(!0);
+        }
+        self.wait_for_distributor();
+        for irq in 32..(self.lines as usize) {
+            self.gicd.ipriorityr()[irq].set(DEFAULT_PRIORITY);
+            self.gicd.irouter()[irq].set(here);
+        }
+        // level-sensitive, which is what every device we drive wants
+        for reg in 2..(self.lines as usize / 16) {
+            self.gicd.icfgr()[reg].set(0);
+        }
+
+        self.gicd.ctlr().write(GICD_CTLR::are_ns::SET);
+        self.wait_for_distributor();
+        self.gicd.ctlr().write(GICD_CTLR::are_ns::SET + GICD_CTLR::enable_grp1::SET);
+        self.wait_for_distributor();
+    }
+
+    fn in_range(&self, irq: Irq) -> bool {
+        irq < self.lines
+    }
+}
+
+impl InterruptController for GicV3 {
+    fn init_cpu(&mut self) {
+        ICC_SRE_EL1::set(ICC_SRE_EL1::get() | 1);
+        asm::isb();
+        assert!(ICC_SRE_EL1::get() & 1 == 1, "gicv3: no system register interface at el1");
+
+        let mpidr = smp::current_mpidr();
+        let redist = self.find_redistributor(mpidr)
+            .unwrap_or_else(|| panic!("gicv3: no redistributor for mpidr {:#x}", mpidr));
+        self.redists[percpu::this().core_id()] = redist;
+
+        if self.lines == 0 {
+            self.init_distributor();
+        }
+
+        let mut redist = self.redist();
+        redist.waker().modify(GICR_WAKER::processor_sleep::CLEAR);
+        wait_for("the redistributor to wake", || {
+            !redist.waker().is_set(GICR_WAKER::children_asleep)
+        });
+
+        // sgis are always enabled; ppis wait until someone asks for them
+        redist.igroupr0().set(!0);
+        redist.icenabler0().set(0xffff_0000);
+        redist.isenabler0().set(0x0000_ffff);
+        for irq in 0..32 {
+            redist.ipriorityr()[irq].set(DEFAULT_PRIORITY);
+        }
+        wait_for("a redistributor write", || !redist.ctlr().is_set(GICR_CTLR::rwp));
+
+        // let every priority through, don't split priorities into groups, and take
+        // group 1 interrupts
+        ICC_PMR_EL1::set(0xff);
+        ICC_BPR1_EL1::set(0);
+        ICC_IGRPEN1_EL1::set(1);
+        asm::isb();
+    }
+
+    fn enable(&mut self, irq: Irq) {
+        if irq < 32 {
+            self.redist().isenabler0().set(1 << irq);
+        } else if self.in_range(irq) {
+            self.gicd.isenabler()[irq as usize / 32].set(1 << (irq % 32));
+        }
+    }
+
+    fn disable(&mut self, irq: Irq) {
+        if irq < 32 {
+            let mut redist = self.redist();
+            redist.icenabler0().set(1 << irq);
+            wait_for("a redistributor write", || !redist.ctlr().is_set(GICR_CTLR::rwp));
+        } else if self.in_range(irq) {
+            self.gicd.icenabler()[irq as usize / 32].set(1 << (irq % 32));
+            self.wait_for_distributor();
+        }
+    }
+
+    fn set_priority(&mut self, irq: Irq, priority: u8) {
+        if irq < 32 {
+            self.redist().ipriorityr()[irq as usize].set(priority);
+        } else if self.in_range(irq) {
+            self.gicd.ipriorityr()[irq as usize].set(priority);
+        }
+    }
+
+    fn set_target(&mut self, irq: Irq, core: usize) {
+        // sgis and ppis always go to the core they belong to
+        if irq >= 32 && self.in_range(irq) {
+            let mpidr = smp::CORE_MPIDRS[core].load(Ordering::Acquire);
+            self.gicd.irouter()[irq as usize].set(routing_affinity(mpidr));
+        }
+    }
+
+    fn acknowledge(&mut self) -> Option<Irq> {
+        let irq = (ICC_IAR1_EL1::get() & 0xff_ffff) as u32;
+        if irq >= FIRST_SPECIAL {
+            None
+        } else {
+            Some(irq)
+        }
+    }
+
+    fn end_of_interrupt(&mut self, irq: Irq) {
+        ICC_EOIR1_EL1::set(irq as u64);
+    }
+
+    fn send_sgi(&mut self, sgi: Irq, target: SgiTarget) {
+        let sgi = (sgi as u64) << 24;
+        let value = match target {
+            // the interrupt routing mode bit
+            SgiTarget::AllOthers => sgi | (1 << 40),
+        };
+        // make sure whatever the target is about to look at is visible to it
+        asm::dsb::ishst();
+        ICC_SGI1R_EL1::set(value);
+        asm::isb();
+    }
+}
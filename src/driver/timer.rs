@@ -0,0 +1,49 @@
+//! the arm generic timer: the system counter, and each core's el1 physical timer. it's
+//! all system registers, so unlike the other drivers there's no register block. see
+//! chapter d11 of the armv8-a architecture reference manual.
+//!
+//! `el2_lower_to_el1` sets `CNTHCTL_EL2` so that el1 can use the physical timer.
+
+use cortex_a::registers::{CNTFRQ_EL0, CNTPCT_EL0, CNTP_CTL_EL0};
+use tock_registers::interfaces::{Readable, Writeable};
+use crate::asm;
+
+/// the counter's frequency in hz, as the firmware programmed it.
+pub fn frequency() -> u64 {
+    CNTFRQ_EL0.get()
+}
+
+/// the system counter, which counts up at `frequency()` from some time around reset.
+pub fn counter() -> u64 {
+    // without the isb, the read can happen early, out of order with whatever we're
+    // trying to time
+    asm::isb();
+    CNTPCT_EL0.get()
+}
+
+/// raise the calling core's timer interrupt once the counter reaches `deadline`,
+/// replacing whatever deadline was there.
+pub fn set_deadline(deadline: u64) {
+    unsafe { asm!("msr cntp_cval_el0, {}", in(reg) deadline, options(nomem, nostack)) };
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+    asm::isb();
+}
+
+/// stop the calling core's timer, which also drops its interrupt.
+pub fn stop() {
+    CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::CLEAR + CNTP_CTL_EL0::IMASK::SET);
+    asm::isb();
+}
+
+/// generate an event, to wake up `wfe`, every time bit `bit` of the counter flips from
+/// 0 to 1, which is every `2^(bit + 1)` ticks. `bit` must be less than 16.
+pub fn enable_event_stream(bit: u32) {
+    debug_assert!(bit < 16);
+    let mut cntkctl: u64;
+    unsafe { asm!("mrs {}, cntkctl_el1", out(reg) cntkctl, options(nomem, nostack)) };
+    // evnti in bits 4-7, evntdir in bit 3, evnten in bit 2
+    cntkctl &= !0xfc;
+    cntkctl |= ((bit as u64) << 4) | (1 << 2);
+    unsafe { asm!("msr cntkctl_el1, {}", in(reg) cntkctl, options(nomem, nostack)) };
+    asm::isb();
+}
@@ -6,3 +6,58 @@ pub mod pl011;
 
 pub use pc16550d::Pc16550d;
 pub use pl011::Pl011;
+
+use core::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// the shape of each character on the wire.
+pub struct Framing {
+    /// 5 through 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Framing {
+    /// 8n1, which is what everyone uses.
+    pub const EIGHT_N_ONE: Framing = Framing {
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+    };
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// the uart's clock can't be divided down to this baud rate.
+    Baud { clock_hz: u32, baud: u32 },
+    /// the uart can't send characters with this many data bits.
+    DataBits(u8),
+    /// the uart stayed busy, and wouldn't let us change its settings.
+    TimedOut,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Baud { clock_hz, baud } => {
+                write!(f, "can't get {} baud from a {} Hz clock", baud, clock_hz)
+            }
+            ConfigError::DataBits(bits) => write!(f, "can't do {} data bits", bits),
+            ConfigError::TimedOut => write!(f, "timed out waiting for the uart to go idle"),
+        }
+    }
+}
@@ -3,11 +3,25 @@
 use tock_registers::{
     register_bitfields,
     registers::{ReadOnly, ReadWrite, WriteOnly},
-    interfaces::{Readable, Writeable}
+    interfaces::{Readable, Writeable, ReadWriteable},
+    LocalRegisterCopy,
 };
-use crate::{console::Console, asm::block_until};
+use crate::console::{Console, LineError, LineErrorCounts, WEDGED_TIMEOUT};
+use crate::time::{self, Instant, Wait};
+use crate::ringbuf::RingBuffer;
+use super::{ConfigError, Framing, Parity, StopBits};
 use core::fmt::{self, Write};
 
+/// how many bytes we buffer in each direction once we're interrupt-driven.
+const RING_SIZE: usize = 1024;
+
+/// how many bytes we can write at once after `LSR::trans_hold_reg_empty` says the tx
+/// fifo is empty. the designware uart in the rk3399 has 64, but a 16550 has 16.
+const TX_FIFO_DEPTH: usize = 16;
+
+/// how many times the irq handler will ask `IIR` what happened before giving up.
+const MAX_IIR_READS: usize = 32;
+
 register_bitfields! {
     u8,
     /// Receiver Buffer Register
@@ -432,11 +446,23 @@ register_bitfields! {
         /// This register is for programmers to use as a temporary
         /// storage space.
         temp_store_space OFFSET(0) NUMBITS(8) []
+    ],
+    /// UART Status Register
+    ///
+    /// word 31, read-only. this is a designware extension, not part of the 16550.
+    USR [
+        /// UART Busy. This indicates that a serial transfer is in
+        /// progress. Reading this register also clears a pending
+        /// busy detect interrupt.
+        busy OFFSET(0) NUMBITS(1) [
+            Idle = 0,
+            Busy = 1
+        ]
     ]
 }
 
 define_register_block! {
-    pub Pc16550d {
+    Registers {
         0x00 => rbr: ReadOnly<u8, RBR::Register>,
         0x00 => thr: WriteOnly<u8, THR::Register>,
         0x00 => dll: ReadWrite<u8, DLL::Register>,
@@ -449,22 +475,328 @@ define_register_block! {
         0x14 => lsr: ReadWrite<u8, LSR::Register>,
         0x18 => msr: ReadWrite<u8, MSR::Register>,
         0x1c => scr: ReadWrite<u8, SCR::Register>,
+        0x7c => usr: ReadOnly<u8, USR::Register>,
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// how full the rx fifo gets before the received data available interrupt fires.
+pub enum RxTrigger {
+    OneChar,
+    Quarter,
+    Half,
+    /// two less than full.
+    AlmostFull,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// how empty the tx fifo gets before the thre interrupt fires, when programmable thre
+/// interrupt mode is on.
+pub enum TxTrigger {
+    Empty,
+    TwoChars,
+    Quarter,
+    Half,
+}
+
+pub struct Pc16550d {
+    regs: Registers,
+    /// what we last wrote to `FCR`, which is write-only, minus the self-clearing reset
+    /// bits.
+    fcr: u8,
+    /// whether `enable_interrupts` has happened. until it does, we poll `LSR` and leave
+    /// the rings alone.
+    interrupts: bool,
+    rx: RingBuffer<Result<u8, LineError>, RING_SIZE>,
+    tx: RingBuffer<u8, RING_SIZE>,
+    /// reading `LSR` clears its error bits, so we collect them here until the byte
+    /// they belong to is read.
+    lsr_errors: u8,
+    errors: LineErrorCounts,
+}
+
 unsafe impl Send for Pc16550d {}
 
+impl Pc16550d {
+    pub const unsafe fn new(base: *mut u8) -> Self {
+        Pc16550d {
+            regs: Registers::new(base),
+            fcr: 0,
+            interrupts: false,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            lsr_errors: 0,
+            errors: LineErrorCounts::new(),
+        }
+    }
+
+    /// every read of `LSR` goes through here, so that no error bits are lost.
+    fn read_lsr(&mut self) -> LocalRegisterCopy<u8, LSR::Register> {
+        let lsr = self.regs.lsr().extract();
+        let errors = LSR::break_int::SET + LSR::framing_error::SET
+            + LSR::parity_error::SET + LSR::overrun_error::SET;
+        self.lsr_errors |= lsr.get() & errors.mask();
+        lsr
+    }
+
+    /// read a byte out of `RBR`, along with whatever `LSR` said went wrong receiving
+    /// it.
+    fn read_rbr(&mut self) -> Result<u8, LineError> {
+        self.read_lsr();
+        let lsr = LocalRegisterCopy::<u8, LSR::Register>::new(self.lsr_errors);
+        self.lsr_errors = 0;
+        let byte = self.regs.rbr().get();
+        // a break also shows up as a framing error, so it has to be checked first
+        let result = if lsr.is_set(LSR::break_int) {
+            Err(LineError::Break)
+        } else if lsr.is_set(LSR::framing_error) {
+            Err(LineError::Framing(byte))
+        } else if lsr.is_set(LSR::parity_error) {
+            Err(LineError::Parity(byte))
+        } else if lsr.is_set(LSR::overrun_error) {
+            Err(LineError::Overrun(byte))
+        } else {
+            Ok(byte)
+        };
+        if let Err(e) = result {
+            self.errors.record(e);
+        }
+        result
+    }
+
+    /// set the uart up from scratch, for `baud` and `framing` given a serial clock of
+    /// `clock_hz`, with fifos on and modem control lines asserted. leaves it polling,
+    /// with every interrupt disabled.
+    pub fn init(&mut self, clock_hz: u32, baud: u32, framing: Framing) -> Result<(), ConfigError> {
+        self.disable_interrupts();
+        self.regs.ier().set(0);
+        self.set_fifos(RxTrigger::Quarter, TxTrigger::Empty);
+        self.set_baud(clock_hz, baud)?;
+        self.set_framing(framing)?;
+        self.regs.mcr().write(MCR::data_terminal_ready::Asserted + MCR::req_to_send::SET);
+        self.rx.clear();
+        Ok(())
+    }
+
+    /// wait for the uart to go idle, which the designware uart wants before it'll take
+    /// a write to `LCR`. it stays busy while there's anything in the rx fifo, so that
+    /// gets flushed.
+    fn wait_until_idle(&mut self) -> Result<(), ConfigError> {
+        self.regs.fcr().set(self.fcr | FCR::rcvr_fifo_reset::SET.value);
+        let idle = || self.regs.usr().matches_all(USR::busy::Idle);
+        time::block_for(idle, WEDGED_TIMEOUT, Wait::Spin).map_err(|_| ConfigError::TimedOut)
+    }
+
+    /// program the divisor latch for `baud`, given a serial clock of `clock_hz`.
+    pub fn set_baud(&mut self, clock_hz: u32, baud: u32) -> Result<(), ConfigError> {
+        // the divisor is clock / (16 * baud), rounded to nearest
+        let divisor = baud.checked_mul(16)
+            .filter(|&d| d != 0)
+            .map(|d| (clock_hz + d / 2) / d)
+            .filter(|&divisor| divisor != 0 && divisor <= 0xffff)
+            .ok_or(ConfigError::Baud { clock_hz, baud })?;
+
+        self.wait_until_idle()?;
+        self.regs.lcr().modify(LCR::div_lat_access::Latched);
+        self.regs.dll().write(DLL::baud_rate_divisor_L.val(divisor as u8));
+        self.regs.dlm().write(DLM::baud_rate_divisor_H.val((divisor >> 8) as u8));
+        self.regs.lcr().modify(LCR::div_lat_access::Unlatched);
+        Ok(())
+    }
+
+    /// program the word length, stop bits and parity.
+    pub fn set_framing(&mut self, framing: Framing) -> Result<(), ConfigError> {
+        let data_length = match framing.data_bits {
+            5 => LCR::data_length_sel::Five,
+            6 => LCR::data_length_sel::Six,
+            7 => LCR::data_length_sel::Seven,
+            8 => LCR::data_length_sel::Eight,
+            bits => return Err(ConfigError::DataBits(bits)),
+        };
+        let parity = match framing.parity {
+            Parity::None => LCR::parity_en::Disabled,
+            Parity::Odd => LCR::parity_en::Enabled + LCR::even_parity_sel::OddOnes,
+            Parity::Even => LCR::parity_en::Enabled + LCR::even_parity_sel::EvenOnes,
+        };
+        let stop_bits = match framing.stop_bits {
+            StopBits::One => LCR::stop_bits_num::OneBit,
+            StopBits::Two => LCR::stop_bits_num::TwoBit,
+        };
+
+        self.wait_until_idle()?;
+        self.regs.lcr().write(data_length + parity + stop_bits + LCR::div_lat_access::Unlatched);
+        Ok(())
+    }
+
+    /// turn the fifos on, emptying them, with the given interrupt trigger levels.
+    pub fn set_fifos(&mut self, rx_trigger: RxTrigger, tx_trigger: TxTrigger) {
+        let rx_trigger = match rx_trigger {
+            RxTrigger::OneChar => FCR::rcvr_trigger::OneChar,
+            RxTrigger::Quarter => FCR::rcvr_trigger::Quarter,
+            RxTrigger::Half => FCR::rcvr_trigger::Half,
+            RxTrigger::AlmostFull => FCR::rcvr_trigger::Almost,
+        };
+        let tx_trigger = match tx_trigger {
+            TxTrigger::Empty => FCR::tx_empty_trigger::Empty,
+            TxTrigger::TwoChars => FCR::tx_empty_trigger::Almost,
+            TxTrigger::Quarter => FCR::tx_empty_trigger::Quarter,
+            TxTrigger::Half => FCR::tx_empty_trigger::Half,
+        };
+        let fcr = FCR::fifo_en::Enabled + rx_trigger + tx_trigger;
+        self.fcr = fcr.value;
+        self.regs.fcr().write(fcr + FCR::rcvr_fifo_reset::SET + FCR::xmit_fifo_reset::SET);
+    }
+
+    /// turn the fifos off, so that every byte goes straight through `RBR` and `THR`.
+    pub fn disable_fifos(&mut self) {
+        self.fcr = 0;
+        self.regs.fcr().set(0);
+    }
+
+    /// move everything the uart has received to the rx ring.
+    fn drain_rx(&mut self) {
+        while self.read_lsr().matches_all(LSR::data_ready::Data) {
+            let result = self.read_rbr();
+            if self.rx.push(result).is_err() {
+                self.errors.dropped += 1;
+            }
+        }
+    }
+
+    /// if the tx fifo is empty, refill it from the tx ring, and leave the thre
+    /// interrupt enabled only if there's more to come.
+    fn fill_tx(&mut self) {
+        if self.read_lsr().matches_all(LSR::trans_hold_reg_empty::Empty) {
+            for _ in 0..TX_FIFO_DEPTH {
+                match self.tx.pop() {
+                    Some(byte) => self.regs.thr().set(byte),
+                    None => break,
+                }
+            }
+        }
+        if self.tx.is_empty() {
+            self.regs.ier().modify(IER::trans_hold_empty_int_en::Disabled);
+        } else {
+            self.regs.ier().modify(IER::trans_hold_empty_int_en::Enabled);
+        }
+    }
+}
+
 impl Console for Pc16550d {
     fn can_write(&mut self) -> bool {
-        self.lsr().matches_all(LSR::trans_hold_reg_empty::Empty)
+        if self.interrupts {
+            // whoever's asking might have irqs masked, so don't count on the irq
+            // handler to make room
+            self.fill_tx();
+            !self.tx.is_full()
+        } else {
+            self.read_lsr().matches_all(LSR::trans_hold_reg_empty::Empty)
+        }
     }
     unsafe fn unchecked_write_byte(&mut self, b: u8) {
-        self.thr().set(b);
+        if self.interrupts {
+            // `can_write` made room
+            let _ = self.tx.push(b);
+            self.fill_tx();
+        } else {
+            self.regs.thr().set(b);
+        }
+    }
+    fn write(&mut self, buf: &[u8]) -> usize {
+        if !self.interrupts {
+            if !self.read_lsr().matches_all(LSR::trans_hold_reg_empty::Empty) {
+                return 0;
+            }
+            // an empty `THR` means an empty fifo, if there is one
+            let room = if self.fcr == 0 { 1 } else { TX_FIFO_DEPTH };
+            let written = buf.len().min(room);
+            for &byte in &buf[..written] {
+                self.regs.thr().set(byte);
+            }
+            return written;
+        }
+        // make room in the ring first, then top the fifo up from it
+        self.fill_tx();
+        let mut written = 0;
+        for &byte in buf {
+            if self.tx.push(byte).is_err() {
+                break;
+            }
+            written += 1;
+        }
+        self.fill_tx();
+        written
     }
     fn can_read(&mut self) -> bool {
-        self.lsr().matches_all(LSR::data_ready::Data)
+        if self.interrupts {
+            self.drain_rx();
+            !self.rx.is_empty()
+        } else {
+            self.read_lsr().matches_all(LSR::data_ready::Data)
+        }
+    }
+    unsafe fn unchecked_read_byte(&mut self) -> Result<u8, LineError> {
+        if self.interrupts {
+            self.rx.pop().unwrap_or(Ok(0))
+        } else {
+            self.read_rbr()
+        }
+    }
+    fn line_errors(&self) -> LineErrorCounts {
+        self.errors
+    }
+    fn enable_interrupts(&mut self) -> bool {
+        if self.fcr == 0 {
+            self.set_fifos(RxTrigger::Quarter, TxTrigger::Empty);
+        }
+        self.regs.ier().write(
+            IER::receive_data_available_int_en::Enabled
+                + IER::receive_line_status_int_en::Enabled
+        );
+        // the interrupt line goes through out2 on a real 16550; the designware uart
+        // ignores it
+        self.regs.mcr().modify(MCR::out2::Asserted);
+        self.interrupts = true;
+        self.drain_rx();
+        true
+    }
+    fn disable_interrupts(&mut self) {
+        if !self.interrupts {
+            return;
+        }
+        self.regs.ier().set(0);
+        self.interrupts = false;
+        while let Some(byte) = self.tx.pop() {
+            if self.write_until(&[byte], Instant::now() + WEDGED_TIMEOUT) == 0 {
+                // the uart's wedged, so the rest would never get out either
+                self.tx.clear();
+            }
+        }
     }
-    unsafe fn unchecked_read_byte(&mut self) -> u8 {
-        self.rbr().get()
+    fn handle_irq(&mut self) {
+        for _ in 0..MAX_IIR_READS {
+            match self.regs.iir().read_as_enum(IIR::int_id) {
+                Some(IIR::int_id::Value::ReceiveAvail) | Some(IIR::int_id::Value::CharTimeout) => {
+                    self.drain_rx();
+                }
+                Some(IIR::int_id::Value::ThrEmpty) => {
+                    self.fill_tx();
+                }
+                Some(IIR::int_id::Value::ReceiveStatus) => {
+                    // reading `LSR` clears it, and `drain_rx` does
+                    self.drain_rx();
+                }
+                Some(IIR::int_id::Value::ModemStatus) => {
+                    // and reading `MSR` clears this one
+                    self.regs.msr().get();
+                }
+                Some(IIR::int_id::Value::BusyDetect) => {
+                    // someone wrote `LCR` while the uart was busy. reading `USR`
+                    // clears it
+                    self.regs.usr().get();
+                }
+                Some(IIR::int_id::Value::None) | None => return,
+            }
+        }
     }
 }
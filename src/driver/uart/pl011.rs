@@ -3,12 +3,17 @@
 use tock_registers::{
     register_bitfields,
     registers::{ReadOnly, ReadWrite, WriteOnly},
-    interfaces::{Readable, Writeable},
+    interfaces::{Readable, Writeable, ReadWriteable},
+    LocalRegisterCopy,
 };
-use crate::{asm::block_until};
-use crate::console::Console;
+use crate::console::{Console, LineError, LineErrorCounts, WEDGED_TIMEOUT};
+use crate::time::{self, Instant, Wait};
+use crate::ringbuf::RingBuffer;
+use super::{ConfigError, Framing, Parity, StopBits};
+
+/// how many bytes we buffer in each direction once we're interrupt-driven.
+const RING_SIZE: usize = 1024;
 
-// there are a lot more registers, but i don't care about them
 register_bitfields! {
     u16,
     /// Data Register
@@ -34,29 +39,335 @@ register_bitfields! {
         data_carrier_detect OFFSET(2) NUMBITS(1) [],
         data_set_ready OFFSET(1) NUMBITS(1) [],
         clear_to_send OFFSET(0) NUMBITS(1) []
+    ],
+    /// Integer Baud Rate Register
+    ///
+    /// at offset 0x24. the baud rate divisor is `IBRD + FBRD / 64`, and the baud rate is
+    /// the reference clock over 16 times that.
+    IBRD [
+        divisor OFFSET(0) NUMBITS(16) []
+    ],
+    /// Fractional Baud Rate Register
+    ///
+    /// at offset 0x28.
+    FBRD [
+        divisor OFFSET(0) NUMBITS(6) []
+    ],
+    /// Line Control Register
+    ///
+    /// at offset 0x2c. writing it is what latches `IBRD` and `FBRD`, so it has to come
+    /// after them.
+    LCR_H [
+        stick_parity OFFSET(7) NUMBITS(1) [],
+        word_length OFFSET(5) NUMBITS(2) [
+            Five = 0b00,
+            Six = 0b01,
+            Seven = 0b10,
+            Eight = 0b11
+        ],
+        fifo_enable OFFSET(4) NUMBITS(1) [],
+        two_stop_bits OFFSET(3) NUMBITS(1) [],
+        even_parity OFFSET(2) NUMBITS(1) [],
+        parity_enable OFFSET(1) NUMBITS(1) [],
+        send_break OFFSET(0) NUMBITS(1) []
+    ],
+    /// Control Register
+    ///
+    /// at offset 0x30. only change it with `uart_enable` clear.
+    CR [
+        cts_enable OFFSET(15) NUMBITS(1) [],
+        rts_enable OFFSET(14) NUMBITS(1) [],
+        out2 OFFSET(13) NUMBITS(1) [],
+        out1 OFFSET(12) NUMBITS(1) [],
+        request_to_send OFFSET(11) NUMBITS(1) [],
+        data_transmit_ready OFFSET(10) NUMBITS(1) [],
+        recv_enable OFFSET(9) NUMBITS(1) [],
+        trans_enable OFFSET(8) NUMBITS(1) [],
+        loopback_enable OFFSET(7) NUMBITS(1) [],
+        uart_enable OFFSET(0) NUMBITS(1) []
+    ],
+    /// Interrupt FIFO Level Select Register
+    ///
+    /// at offset 0x34. each field picks the fifo level at which its interrupt fires.
+    IFLS [
+        recv_level OFFSET(3) NUMBITS(3) [
+            OneEighth = 0b000,
+            OneQuarter = 0b001,
+            OneHalf = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEighths = 0b100
+        ],
+        trans_level OFFSET(0) NUMBITS(3) [
+            OneEighth = 0b000,
+            OneQuarter = 0b001,
+            OneHalf = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEighths = 0b100
+        ]
+    ],
+    /// Interrupt Mask Set/Clear, Raw Interrupt Status, Masked Interrupt Status and
+    /// Interrupt Clear Registers, which all share a layout
+    ///
+    /// at offsets 0x38, 0x3c, 0x40 and 0x44
+    INT [
+        overrun OFFSET(10) NUMBITS(1) [],
+        break_error OFFSET(9) NUMBITS(1) [],
+        parity_error OFFSET(8) NUMBITS(1) [],
+        framing_error OFFSET(7) NUMBITS(1) [],
+        recv_timeout OFFSET(6) NUMBITS(1) [],
+        trans OFFSET(5) NUMBITS(1) [],
+        recv OFFSET(4) NUMBITS(1) [],
+        dsr_modem OFFSET(3) NUMBITS(1) [],
+        dcd_modem OFFSET(2) NUMBITS(1) [],
+        cts_modem OFFSET(1) NUMBITS(1) [],
+        ri_modem OFFSET(0) NUMBITS(1) []
+    ],
+    /// DMA Control Register
+    ///
+    /// at offset 0x48.
+    DMACR [
+        dma_on_error OFFSET(2) NUMBITS(1) [],
+        trans_dma_enable OFFSET(1) NUMBITS(1) [],
+        recv_dma_enable OFFSET(0) NUMBITS(1) []
     ]
 }
 
 define_register_block! {
-    pub Pl011 {
+    Registers {
         0x00 => dr: ReadWrite<u16, DR::Register>,
         0x18 => fr: ReadOnly<u16, FR::Register>,
+        0x24 => ibrd: ReadWrite<u16, IBRD::Register>,
+        0x28 => fbrd: ReadWrite<u16, FBRD::Register>,
+        0x2c => lcr_h: ReadWrite<u16, LCR_H::Register>,
+        0x30 => cr: ReadWrite<u16, CR::Register>,
+        0x34 => ifls: ReadWrite<u16, IFLS::Register>,
+        0x38 => imsc: ReadWrite<u16, INT::Register>,
+        0x3c => ris: ReadOnly<u16, INT::Register>,
+        0x40 => mis: ReadOnly<u16, INT::Register>,
+        0x44 => icr: WriteOnly<u16, INT::Register>,
+        0x48 => dmacr: ReadWrite<u16, DMACR::Register>,
     }
 }
 
+pub struct Pl011 {
+    regs: Registers,
+    /// whether `enable_interrupts` has happened. until it does, we poll the hardware
+    /// fifos directly and leave the rings alone.
+    interrupts: bool,
+    rx: RingBuffer<Result<u8, LineError>, RING_SIZE>,
+    tx: RingBuffer<u8, RING_SIZE>,
+    errors: LineErrorCounts,
+}
+
 unsafe impl Send for Pl011 {}
 
+impl Pl011 {
+    pub const unsafe fn new(base: *mut u8) -> Self {
+        Pl011 {
+            regs: Registers::new(base),
+            interrupts: false,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            errors: LineErrorCounts::new(),
+        }
+    }
+
+    /// read a byte out of `DR`, along with whatever went wrong receiving it.
+    fn read_dr(&mut self) -> Result<u8, LineError> {
+        let dr = self.regs.dr().extract();
+        let result = decode_dr(dr);
+        if let Err(e) = result {
+            self.errors.record(e);
+        }
+        result
+    }
+
+    /// set the uart up from scratch, for `baud` and `framing` given a reference clock
+    /// of `clock_hz`, following section 3.3.8 of the trm: disable it, let it finish
+    /// sending, flush the fifos, program it, and enable it again. leaves it polling,
+    /// with every interrupt masked.
+    pub fn init(&mut self, clock_hz: u32, baud: u32, framing: Framing) -> Result<(), ConfigError> {
+        // the divisor is clock / (16 * baud), in 16.6 fixed point, rounded to nearest
+        let divisor = baud.checked_mul(16)
+            .filter(|&d| d != 0)
+            .map(|d| ((clock_hz as u64 * 64 * 2 / d as u64) + 1) / 2)
+            .ok_or(ConfigError::Baud { clock_hz, baud })?;
+        let (ibrd, fbrd) = (divisor >> 6, divisor & 0x3f);
+        if ibrd == 0 || ibrd > 0xffff || (ibrd == 0xffff && fbrd != 0) {
+            return Err(ConfigError::Baud { clock_hz, baud });
+        }
+        let word_length = match framing.data_bits {
+            5 => LCR_H::word_length::Five,
+            6 => LCR_H::word_length::Six,
+            7 => LCR_H::word_length::Seven,
+            8 => LCR_H::word_length::Eight,
+            bits => return Err(ConfigError::DataBits(bits)),
+        };
+        let parity = match framing.parity {
+            Parity::None => LCR_H::parity_enable::CLEAR,
+            Parity::Odd => LCR_H::parity_enable::SET + LCR_H::even_parity::CLEAR,
+            Parity::Even => LCR_H::parity_enable::SET + LCR_H::even_parity::SET,
+        };
+        let stop_bits = match framing.stop_bits {
+            StopBits::One => LCR_H::two_stop_bits::CLEAR,
+            StopBits::Two => LCR_H::two_stop_bits::SET,
+        };
+
+        self.disable_interrupts();
+        self.regs.cr().write(CR::uart_enable::CLEAR);
+        // if it never finishes, reprogramming it from scratch is our best hope of
+        // unwedging it anyway
+        let _ = time::block_for(|| !self.regs.fr().is_set(FR::busy), WEDGED_TIMEOUT, Wait::Spin);
+        // turning the fifos off flushes them
+        self.regs.lcr_h().modify(LCR_H::fifo_enable::CLEAR);
+
+        self.regs.imsc().set(0);
+        self.regs.icr().set(0x7ff);
+        self.regs.dmacr().set(0);
+        self.regs.ibrd().write(IBRD::divisor.val(ibrd as u16));
+        self.regs.fbrd().write(FBRD::divisor.val(fbrd as u16));
+        self.regs.lcr_h().write(word_length + parity + stop_bits + LCR_H::fifo_enable::SET);
+
+        self.regs.cr().write(CR::uart_enable::SET + CR::trans_enable::SET + CR::recv_enable::SET);
+        self.rx.clear();
+        Ok(())
+    }
+
+    /// move everything in the hardware rx fifo to the rx ring.
+    fn drain_rx(&mut self) {
+        while !self.regs.fr().is_set(FR::recv_fifo_empty) {
+            let result = self.read_dr();
+            if self.rx.push(result).is_err() {
+                self.errors.dropped += 1;
+            }
+        }
+    }
+
+    /// move as much of the tx ring to the hardware tx fifo as fits, and leave the tx
+    /// interrupt unmasked only if there's more to come.
+    fn fill_tx(&mut self) {
+        while !self.tx.is_empty() && !self.regs.fr().is_set(FR::trans_fifo_full) {
+            let byte = self.tx.pop().unwrap();
+            self.regs.dr().set(byte as u16);
+        }
+        if self.tx.is_empty() {
+            self.regs.imsc().modify(INT::trans::CLEAR);
+        } else {
+            self.regs.imsc().modify(INT::trans::SET);
+        }
+    }
+}
+
 impl Console for Pl011 {
     unsafe fn unchecked_write_byte(&mut self, byte: u8) {
-        self.dr().set(byte as u16);
+        if self.interrupts {
+            // `can_write` made room
+            let _ = self.tx.push(byte);
+            self.fill_tx();
+        } else {
+            self.regs.dr().set(byte as u16);
+        }
     }
     fn can_write(&mut self) -> bool {
-        !self.fr().is_set(FR::trans_fifo_full)
+        if self.interrupts {
+            // whoever's asking might have irqs masked, so don't count on the irq
+            // handler to make room
+            self.fill_tx();
+            !self.tx.is_full()
+        } else {
+            !self.regs.fr().is_set(FR::trans_fifo_full)
+        }
     }
-    unsafe fn unchecked_read_byte(&mut self) -> u8 {
-        self.dr().get() as u8
+    unsafe fn unchecked_read_byte(&mut self) -> Result<u8, LineError> {
+        if self.interrupts {
+            self.rx.pop().unwrap_or(Ok(0))
+        } else {
+            self.read_dr()
+        }
     }
     fn can_read(&mut self) -> bool {
-        !self.fr().is_set(FR::recv_fifo_empty)
+        if self.interrupts {
+            self.drain_rx();
+            !self.rx.is_empty()
+        } else {
+            !self.regs.fr().is_set(FR::recv_fifo_empty)
+        }
+    }
+    fn write(&mut self, buf: &[u8]) -> usize {
+        if !self.interrupts {
+            let mut written = 0;
+            while written < buf.len() && !self.regs.fr().is_set(FR::trans_fifo_full) {
+                self.regs.dr().set(buf[written] as u16);
+                written += 1;
+            }
+            return written;
+        }
+        // make room in the ring first, then top the fifo up from it
+        self.fill_tx();
+        let mut written = 0;
+        for &byte in buf {
+            if self.tx.push(byte).is_err() {
+                break;
+            }
+            written += 1;
+        }
+        self.fill_tx();
+        written
+    }
+    fn line_errors(&self) -> LineErrorCounts {
+        self.errors
+    }
+    fn enable_interrupts(&mut self) -> bool {
+        // interrupt when the rx fifo is half full, or when it's had something in it for
+        // a while, and when the tx fifo is down to an eighth
+        self.regs.ifls().write(IFLS::recv_level::OneHalf + IFLS::trans_level::OneEighth);
+        self.regs.icr().set(0x7ff);
+        self.regs.imsc().write(INT::recv::SET + INT::recv_timeout::SET);
+        self.interrupts = true;
+        // anything already in the rx fifo won't raise an interrupt by itself
+        self.drain_rx();
+        true
+    }
+    fn disable_interrupts(&mut self) {
+        if !self.interrupts {
+            return;
+        }
+        self.regs.imsc().set(0);
+        self.interrupts = false;
+        while let Some(byte) = self.tx.pop() {
+            if self.write_until(&[byte], Instant::now() + WEDGED_TIMEOUT) == 0 {
+                // the uart's wedged, so the rest would never get out either
+                self.tx.clear();
+            }
+        }
+    }
+    fn handle_irq(&mut self) {
+        let status = self.regs.mis().extract();
+        if status.matches_any(INT::recv::SET + INT::recv_timeout::SET) {
+            self.drain_rx();
+        }
+        if status.is_set(INT::trans) {
+            self.fill_tx();
+        }
+        // reading and writing `dr` clears the rx and tx interrupts by itself; this gets
+        // the rest
+        self.regs.icr().set(status.get());
+    }
+}
+
+/// split a value read from `DR` into the byte, or the error it arrived with. a break
+/// also shows up as a framing error, so it has to be checked first.
+fn decode_dr(dr: LocalRegisterCopy<u16, DR::Register>) -> Result<u8, LineError> {
+    let byte = dr.read(DR::data) as u8;
+    if dr.is_set(DR::break_error) {
+        Err(LineError::Break)
+    } else if dr.is_set(DR::framing_error) {
+        Err(LineError::Framing(byte))
+    } else if dr.is_set(DR::parity_error) {
+        Err(LineError::Parity(byte))
+    } else if dr.is_set(DR::overrun_error) {
+        Err(LineError::Overrun(byte))
+    } else {
+        Ok(byte)
     }
 }
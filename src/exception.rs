@@ -0,0 +1,413 @@
+//! the el1 exception vector table, and the rust side of taking an exception.
+//!
+//! every vector saves a full `TrapFrame` on the current stack and calls
+//! `handle_exception`. synchronous exceptions go to whichever handler is registered
+//! for their exception class, irqs go to the irq handler, and anything nobody claims
+//! turns into a panic with the decoded syndrome.
+
+use crate::{asm, percpu};
+use core::fmt;
+use cortex_a::registers::VBAR_EL1;
+use spin::{Once, RwLock};
+use tock_registers::interfaces::Writeable;
+
+#[repr(C)]
+#[derive(Clone)]
+/// the state of the interrupted context, laid out the way the vectors below save it.
+pub struct TrapFrame {
+    pub x: [u64; 31],
+    /// the stack pointer of the interrupted context: `SP_EL0` if it came from el0,
+    /// `SP_EL1` from before the trap frame was pushed if not.
+    pub sp: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
+}
+
+// the offsets in the assembly below depend on this
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == 288);
+
+global_asm!(
+    r#"
+    .section .text.vectors, "ax"
+    .balign 0x800
+    .global __exception_vectors
+__exception_vectors:
+    .irp kind, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+    .balign 0x80
+    sub sp, sp, #288
+    stp x0, x1, [sp, #0]
+    mov x0, #\kind
+    b __exception_common
+    .endr
+
+__exception_common:
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+
+    // the interrupted sp: sp_el0 for kinds 8 and up, which come from el0, or our own
+    // from before the frame was pushed
+    mov x19, x0
+    add x1, sp, #288
+    cmp x19, #8
+    b.lo 1f
+    mrs x1, sp_el0
+1:
+    stp x30, x1, [sp, #240]
+    mrs x2, elr_el1
+    mrs x3, spsr_el1
+    stp x2, x3, [sp, #256]
+    mrs x2, esr_el1
+    mrs x3, far_el1
+    stp x2, x3, [sp, #272]
+
+    mov x0, sp
+    mov x1, x19
+    bl handle_exception
+
+    // handlers may have changed any of these, e.g. to step over an instruction
+    ldp x2, x3, [sp, #256]
+    msr elr_el1, x2
+    msr spsr_el1, x3
+    cmp x19, #8
+    b.lo 2f
+    ldr x1, [sp, #248]
+    msr sp_el0, x1
+2:
+    ldp x0, x1, [sp, #0]
+    ldp x2, x3, [sp, #16]
+    ldp x4, x5, [sp, #32]
+    ldp x6, x7, [sp, #48]
+    ldp x8, x9, [sp, #64]
+    ldp x10, x11, [sp, #80]
+    ldp x12, x13, [sp, #96]
+    ldp x14, x15, [sp, #112]
+    ldp x16, x17, [sp, #128]
+    ldp x18, x19, [sp, #144]
+    ldp x20, x21, [sp, #160]
+    ldp x22, x23, [sp, #176]
+    ldp x24, x25, [sp, #192]
+    ldp x26, x27, [sp, #208]
+    ldp x28, x29, [sp, #224]
+    ldr x30, [sp, #240]
+    add sp, sp, #288
+    eret
+    "#
+);
+
+extern "C" {
+    static __exception_vectors: u64;
+}
+
+/// point this core's `VBAR_EL1` at the vector table. every core has to do this for
+/// itself.
+pub fn init() {
+    unsafe {
+        VBAR_EL1.set(&__exception_vectors as *const u64 as u64);
+    }
+    asm::isb();
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// which of the 16 vectors we came through. the discriminants are the vector numbers.
+pub enum Vector {
+    CurrentSp0Sync,
+    CurrentSp0Irq,
+    CurrentSp0Fiq,
+    CurrentSp0SError,
+    CurrentSpxSync,
+    CurrentSpxIrq,
+    CurrentSpxFiq,
+    CurrentSpxSError,
+    Lower64Sync,
+    Lower64Irq,
+    Lower64Fiq,
+    Lower64SError,
+    Lower32Sync,
+    Lower32Irq,
+    Lower32Fiq,
+    Lower32SError,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VectorKind {
+    Sync,
+    Irq,
+    Fiq,
+    SError,
+}
+
+impl Vector {
+    fn from_index(i: u64) -> Vector {
+        use Vector::*;
+        const VECTORS: [Vector; 16] = [
+            CurrentSp0Sync, CurrentSp0Irq, CurrentSp0Fiq, CurrentSp0SError,
+            CurrentSpxSync, CurrentSpxIrq, CurrentSpxFiq, CurrentSpxSError,
+            Lower64Sync, Lower64Irq, Lower64Fiq, Lower64SError,
+            Lower32Sync, Lower32Irq, Lower32Fiq, Lower32SError,
+        ];
+        VECTORS[i as usize]
+    }
+
+    pub fn kind(self) -> VectorKind {
+        match self as u8 & 3 {
+            0 => VectorKind::Sync,
+            1 => VectorKind::Irq,
+            2 => VectorKind::Fiq,
+            _ => VectorKind::SError,
+        }
+    }
+
+    fn source(self) -> &'static str {
+        match self as u8 >> 2 {
+            0 => "the current el, using sp_el0",
+            1 => "the current el, using sp_elx",
+            2 => "a lower el, in aarch64",
+            _ => "a lower el, in aarch32",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// `ESR_ELx.EC`, the exception class, which says what the rest of the syndrome means.
+pub struct ExceptionClass(pub u8);
+
+impl ExceptionClass {
+    pub const UNKNOWN: Self = Self(0x00);
+    pub const WFI_WFE: Self = Self(0x01);
+    pub const FP_ACCESS: Self = Self(0x07);
+    pub const ILLEGAL_STATE: Self = Self(0x0e);
+    pub const SVC64: Self = Self(0x15);
+    pub const HVC64: Self = Self(0x16);
+    pub const SMC64: Self = Self(0x17);
+    pub const SYSREG: Self = Self(0x18);
+    pub const INSTRUCTION_ABORT_LOWER: Self = Self(0x20);
+    pub const INSTRUCTION_ABORT_SAME: Self = Self(0x21);
+    pub const PC_ALIGNMENT: Self = Self(0x22);
+    pub const DATA_ABORT_LOWER: Self = Self(0x24);
+    pub const DATA_ABORT_SAME: Self = Self(0x25);
+    pub const SP_ALIGNMENT: Self = Self(0x26);
+    pub const FP_EXCEPTION64: Self = Self(0x2c);
+    pub const SERROR: Self = Self(0x2f);
+    pub const BREAKPOINT_LOWER: Self = Self(0x30);
+    pub const BREAKPOINT_SAME: Self = Self(0x31);
+    pub const SOFTWARE_STEP_LOWER: Self = Self(0x32);
+    pub const SOFTWARE_STEP_SAME: Self = Self(0x33);
+    pub const WATCHPOINT_LOWER: Self = Self(0x34);
+    pub const WATCHPOINT_SAME: Self = Self(0x35);
+    pub const BRK64: Self = Self(0x3c);
+
+    pub fn of_esr(esr: u64) -> Self {
+        Self(((esr >> 26) & 0x3f) as u8)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UNKNOWN => "unknown reason (probably an undefined instruction)",
+            Self::WFI_WFE => "trapped wfi or wfe",
+            Self::FP_ACCESS => "trapped access to simd or floating point",
+            Self::ILLEGAL_STATE => "illegal execution state",
+            Self::SVC64 => "svc from aarch64",
+            Self::HVC64 => "hvc from aarch64",
+            Self::SMC64 => "smc from aarch64",
+            Self::SYSREG => "trapped msr, mrs or system instruction",
+            Self::INSTRUCTION_ABORT_LOWER => "instruction abort from a lower el",
+            Self::INSTRUCTION_ABORT_SAME => "instruction abort without a change in el",
+            Self::PC_ALIGNMENT => "pc alignment fault",
+            Self::DATA_ABORT_LOWER => "data abort from a lower el",
+            Self::DATA_ABORT_SAME => "data abort without a change in el",
+            Self::SP_ALIGNMENT => "sp alignment fault",
+            Self::FP_EXCEPTION64 => "trapped floating point exception from aarch64",
+            Self::SERROR => "serror interrupt",
+            Self::BREAKPOINT_LOWER => "breakpoint from a lower el",
+            Self::BREAKPOINT_SAME => "breakpoint without a change in el",
+            Self::SOFTWARE_STEP_LOWER => "software step from a lower el",
+            Self::SOFTWARE_STEP_SAME => "software step without a change in el",
+            Self::WATCHPOINT_LOWER => "watchpoint from a lower el",
+            Self::WATCHPOINT_SAME => "watchpoint without a change in el",
+            Self::BRK64 => "brk from aarch64",
+            _ => "reserved or aarch32-only exception class",
+        }
+    }
+}
+
+/// the instruction or data fault status code of an abort, which says what kind of
+/// fault it was.
+fn fault_status_name(fsc: u64) -> &'static str {
+    match fsc {
+        0b00_0000..=0b00_0011 => "address size fault",
+        0b00_0100..=0b00_0111 => "translation fault",
+        0b00_1001..=0b00_1011 => "access flag fault",
+        0b00_1101..=0b00_1111 => "permission fault",
+        0b01_0000 => "synchronous external abort",
+        0b01_0100..=0b01_0111 => "synchronous external abort on a table walk",
+        0b01_1000 => "synchronous parity or ecc error",
+        0b10_0001 => "alignment fault",
+        0b11_0000 => "tlb conflict abort",
+        0b11_0100 => "implementation defined fault (lockdown)",
+        0b11_0101 => "implementation defined fault (unsupported exclusive or atomic access)",
+        _ => "reserved fault status code",
+    }
+}
+
+/// the translation table level of a fault whose status code encodes one.
+fn fault_level(fsc: u64) -> Option<u64> {
+    match fsc {
+        0b00_0000..=0b00_1111 | 0b01_0100..=0b01_0111 => Some(fsc & 3),
+        _ => None,
+    }
+}
+
+/// a human readable rendering of a `TrapFrame`'s syndrome and registers.
+pub struct FaultReport<'a> {
+    pub vector: Vector,
+    pub frame: &'a TrapFrame,
+}
+
+impl<'a> FaultReport<'a> {
+    fn fmt_iss(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let esr = self.frame.esr;
+        let iss = esr & 0x1ff_ffff;
+        let ec = ExceptionClass::of_esr(esr);
+        match ec {
+            ExceptionClass::DATA_ABORT_LOWER | ExceptionClass::DATA_ABORT_SAME => {
+                let dfsc = iss & 0x3f;
+                let write = iss & (1 << 6) != 0;
+                write!(f, "\n\t{} on {}", fault_status_name(dfsc), if write { "write" } else { "read" })?;
+                if let Some(level) = fault_level(dfsc) {
+                    write!(f, " at level {}", level)?;
+                }
+                if iss & (1 << 24) != 0 {
+                    let size = 1 << ((iss >> 22) & 3);
+                    let reg = (iss >> 16) & 0x1f;
+                    write!(f, ", {} bytes through x{}", size, reg)?;
+                }
+                if iss & (1 << 8) != 0 {
+                    write!(f, ", by a cache maintenance instruction")?;
+                }
+                if iss & (1 << 7) != 0 {
+                    write!(f, ", during a stage 2 walk for a stage 1 walk")?;
+                }
+                self.fmt_far(f)
+            }
+            ExceptionClass::INSTRUCTION_ABORT_LOWER | ExceptionClass::INSTRUCTION_ABORT_SAME => {
+                let ifsc = iss & 0x3f;
+                write!(f, "\n\t{}", fault_status_name(ifsc))?;
+                if let Some(level) = fault_level(ifsc) {
+                    write!(f, " at level {}", level)?;
+                }
+                self.fmt_far(f)
+            }
+            ExceptionClass::PC_ALIGNMENT | ExceptionClass::WATCHPOINT_LOWER
+                | ExceptionClass::WATCHPOINT_SAME => self.fmt_far(f),
+            ExceptionClass::SVC64 | ExceptionClass::HVC64 | ExceptionClass::SMC64
+                | ExceptionClass::BRK64 => write!(f, "\n\timmediate {:#x}", iss & 0xffff),
+            ExceptionClass::SYSREG => {
+                let op0 = (iss >> 20) & 3;
+                let op2 = (iss >> 17) & 7;
+                let op1 = (iss >> 14) & 7;
+                let crn = (iss >> 10) & 0xf;
+                let rt = (iss >> 5) & 0x1f;
+                let crm = (iss >> 1) & 0xf;
+                let read = iss & 1 != 0;
+                write!(
+                    f, "\n\t{} s{}_{}_c{}_c{}_{} {} x{}",
+                    if read { "mrs" } else { "msr" }, op0, op1, crn, crm, op2,
+                    if read { "into" } else { "from" }, rt,
+                )
+            }
+            ExceptionClass::SERROR => write!(f, "\n\tiss {:#x}", iss),
+            _ => Ok(()),
+        }
+    }
+
+    fn fmt_far(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // FnV, "far not valid", only exists for aborts, and is res0 elsewhere
+        if self.frame.esr & (1 << 10) != 0 {
+            write!(f, "\n\tfar_el1 is not valid")
+        } else {
+            write!(f, "\n\tfaulting address {:#018x}", self.frame.far)
+        }
+    }
+}
+
+impl<'a> fmt::Display for FaultReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = self.frame;
+        let kind = match self.vector.kind() {
+            VectorKind::Sync => "synchronous exception",
+            VectorKind::Irq => "irq",
+            VectorKind::Fiq => "fiq",
+            VectorKind::SError => "serror",
+        };
+        write!(f, "unhandled {} from {} on core {}", kind, self.vector.source(), percpu::this().core_id())?;
+        let ec = ExceptionClass::of_esr(frame.esr);
+        write!(f, "\n\tesr_el1 {:#010x}: {} (ec {:#04x})", frame.esr, ec.name(), ec.0)?;
+        self.fmt_iss(f)?;
+        write!(f, "\n\telr_el1 {:#018x}  spsr_el1 {:#010x}  sp {:#018x}", frame.elr, frame.spsr, frame.sp)?;
+        for (i, x) in frame.x.iter().enumerate() {
+            if i % 4 == 0 {
+                write!(f, "\n\t")?;
+            }
+            write!(f, "x{:<2} {:#018x}  ", i, x)?;
+        }
+        Ok(())
+    }
+}
+
+/// a handler for one exception class. it returns false to decline the exception, in
+/// which case we panic.
+pub type Handler = fn(&mut TrapFrame) -> bool;
+
+static HANDLERS: RwLock<[Option<Handler>; 64]> = RwLock::new([None; 64]);
+
+static IRQ_HANDLER: Once<fn(&mut TrapFrame)> = Once::new();
+
+/// handle synchronous exceptions of class `ec` with `handler`, replacing any handler
+/// already registered for it.
+pub fn register_handler(ec: ExceptionClass, handler: Handler) {
+    HANDLERS.write()[ec.0 as usize] = Some(handler);
+}
+
+/// handle every irq with `handler`. can only be done once.
+pub fn set_irq_handler(handler: fn(&mut TrapFrame)) {
+    IRQ_HANDLER.call_once(|| handler);
+}
+
+#[no_mangle]
+extern "C" fn handle_exception(frame: &mut TrapFrame, vector: u64) {
+    let vector = Vector::from_index(vector);
+    let handled = match vector.kind() {
+        VectorKind::Sync => {
+            percpu::Counters::bump(percpu!(counters.exceptions));
+            let ec = ExceptionClass::of_esr(frame.esr);
+            let handler = HANDLERS.read()[ec.0 as usize];
+            handler.map(|handler| handler(frame)).unwrap_or(false)
+        }
+        VectorKind::Irq => {
+            if let Some(handler) = IRQ_HANDLER.get() {
+                handler(frame);
+                true
+            } else {
+                false
+            }
+        }
+        VectorKind::Fiq | VectorKind::SError => false,
+    };
+    if !handled {
+        panic!("{}", FaultReport { vector, frame });
+    }
+}
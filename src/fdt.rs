@@ -0,0 +1,608 @@
+//! a no_std parser for the flattened device tree (a.k.a. the dtb) that the firmware or
+//! bootloader leaves for us in x0.
+//!
+//! the format is described in the devicetree specification, chapter 5. everything in
+//! the blob is big-endian, and every token in the structure block is 4-byte aligned.
+
+use crate::memory::Paddr;
+use core::{fmt, slice, str};
+use spin::Once;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// the oldest version of the format we know how to read. version 16 is the first one
+/// in which node names are stored unqualified, which everything since 2008 emits.
+const FDT_MIN_VERSION: u32 = 16;
+
+/// the version we actually implement. a blob is readable by us if its
+/// `last_comp_version` is at most this.
+const FDT_VERSION: u32 = 17;
+
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// defaults mandated by the spec for nodes whose parent omits `#address-cells` or
+/// `#size-cells`.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FdtError {
+    /// x0 was zero, which is what we get when nobody passed us a dtb.
+    NullPointer,
+    /// the blob isn't 4-byte aligned, which the spec requires.
+    Misaligned(Paddr),
+    BadMagic(u32),
+    /// the blob is newer than we understand, or older than we're willing to read.
+    UnsupportedVersion { version: u32, last_comp_version: u32 },
+    /// some offset or size in the header points outside `totalsize`.
+    Truncated,
+    /// the structure block doesn't start with a node.
+    NoRoot,
+}
+
+impl fmt::Display for FdtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdtError::NullPointer => write!(f, "no device tree was passed in x0"),
+            FdtError::Misaligned(addr) => write!(f, "device tree at {:x} is not 4-byte aligned", addr),
+            FdtError::BadMagic(magic) => write!(f, "bad device tree magic {:#x}", magic),
+            FdtError::UnsupportedVersion { version, last_comp_version } => write!(
+                f, "unsupported device tree version {} (compatible with {})",
+                version, last_comp_version,
+            ),
+            FdtError::Truncated => write!(f, "device tree is truncated"),
+            FdtError::NoRoot => write!(f, "device tree has no root node"),
+        }
+    }
+}
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let b = bytes.get(offset..offset + 8)?;
+    let mut buf = [0; 8];
+    buf.copy_from_slice(b);
+    Some(u64::from_be_bytes(buf))
+}
+
+const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// read a `cells`-cell big-endian number from the front of `bytes`.
+///
+/// numbers wider than 64 bits (e.g. the 3-cell pci addresses) keep only their low 64
+/// bits, which is where the actual address lives.
+fn read_cells(bytes: &[u8], cells: u32) -> Option<u64> {
+    let mut acc: u64 = 0;
+    for i in 0..cells as usize {
+        acc = (acc << 32) | be32(bytes, i * 4)? as u64;
+    }
+    Some(acc)
+}
+
+/// the null-terminated string at the start of `bytes`, and the number of bytes it
+/// occupies including the terminator.
+fn cstr(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    let len = bytes.iter().position(|&b| b == 0)?;
+    Some((&bytes[..len], len + 1))
+}
+
+#[derive(Copy, Clone)]
+/// a validated device tree blob.
+pub struct DeviceTree<'a> {
+    header: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap: &'a [u8],
+}
+
+impl<'a> DeviceTree<'a> {
+    /// validate the blob at `dtb`.
+    ///
+    /// # safety
+    ///
+    /// `dtb` must either be null or point to memory that nothing else will write for as
+    /// long as the returned `DeviceTree` lives.
+    pub unsafe fn from_paddr(dtb: Paddr) -> Result<Self, FdtError> {
+        let addr = u64::from(dtb);
+        if addr == 0 {
+            return Err(FdtError::NullPointer);
+        }
+        if addr & 3 != 0 {
+            return Err(FdtError::Misaligned(dtb));
+        }
+        let header: &[u8] = slice::from_raw_parts(addr as *const u8, FDT_HEADER_SIZE);
+        let magic = be32(header, 0).unwrap();
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let totalsize = be32(header, 4).unwrap() as usize;
+        Self::from_bytes(slice::from_raw_parts(addr as *const u8, totalsize))
+    }
+
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Self, FdtError> {
+        let field = |n: usize| be32(blob, n * 4).ok_or(FdtError::Truncated);
+
+        let magic = field(0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let totalsize = field(1)? as usize;
+        let off_dt_struct = field(2)? as usize;
+        let off_dt_strings = field(3)? as usize;
+        let off_mem_rsvmap = field(4)? as usize;
+        let version = field(5)?;
+        let last_comp_version = field(6)?;
+        let size_dt_strings = field(8)? as usize;
+        let size_dt_struct = field(9)? as usize;
+
+        if version < FDT_MIN_VERSION || last_comp_version > FDT_VERSION {
+            return Err(FdtError::UnsupportedVersion { version, last_comp_version });
+        }
+
+        if totalsize < FDT_HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        let blob = blob.get(..totalsize).ok_or(FdtError::Truncated)?;
+        let block = |off: usize, size: usize| {
+            off.checked_add(size)
+                .and_then(|end| blob.get(off..end))
+                .ok_or(FdtError::Truncated)
+        };
+
+        let tree = DeviceTree {
+            header: &blob[..FDT_HEADER_SIZE],
+            structs: block(off_dt_struct, size_dt_struct)?,
+            strings: block(off_dt_strings, size_dt_strings)?,
+            mem_rsvmap: Self::mem_rsvmap(blob, off_mem_rsvmap)?,
+        };
+        // so that `root` can't fail
+        tree.parse_root().ok_or(FdtError::NoRoot)?;
+        Ok(tree)
+    }
+
+    /// the memory reservation block at `off`, up to and including the all-zero entry
+    /// which ends it. the spec doesn't say what comes after it, so that's all we have to
+    /// go on.
+    fn mem_rsvmap(blob: &'a [u8], off: usize) -> Result<&'a [u8], FdtError> {
+        let rsvmap = blob.get(off..).ok_or(FdtError::Truncated)?;
+        let mut end = 0;
+        loop {
+            let address = be64(rsvmap, end).ok_or(FdtError::Truncated)?;
+            let size = be64(rsvmap, end + 8).ok_or(FdtError::Truncated)?;
+            end += 16;
+            if address == 0 && size == 0 {
+                return Ok(&rsvmap[..end]);
+            }
+        }
+    }
+
+    /// the address of the blob itself.
+    pub fn paddr(&self) -> Paddr {
+        Paddr::from(self.header.as_ptr() as u64)
+    }
+
+    /// the size in bytes of the whole blob, as declared by its header.
+    pub fn total_size(&self) -> u64 {
+        be32(self.header, 4).unwrap() as u64
+    }
+
+    pub fn root(&self) -> Node<'a> {
+        self.parse_root().expect("from_bytes let through a device tree with no root node")
+    }
+
+    fn parse_root(&self) -> Option<Node<'a>> {
+        Node::parse(
+            self.structs,
+            self.strings,
+            0,
+            DEFAULT_ADDRESS_CELLS,
+            DEFAULT_SIZE_CELLS,
+        )
+    }
+
+    /// every node in the tree, depth first, starting with the root.
+    pub fn nodes(&self) -> Nodes<'a> {
+        let mut nodes = Nodes { stack: [None; MAX_DEPTH], depth: 0 };
+        nodes.stack[0] = Some(self.root().as_children());
+        nodes.depth = 1;
+        nodes
+    }
+
+    /// look up a node by its full path, like `/cpus/cpu@0`.
+    ///
+    /// a path component without a unit address matches a node with one, so `/memory`
+    /// finds `/memory@40000000`.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut node = self.root();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = node.children().find(|child| child.name_matches(component))?;
+        }
+        Some(node)
+    }
+
+    /// every node whose `compatible` list contains `compat`.
+    pub fn compatible_nodes<'c>(&self, compat: &'c str) -> impl Iterator<Item = Node<'a>> + 'c
+    where 'a: 'c,
+    {
+        self.nodes().filter(move |node| node.is_compatible(compat))
+    }
+
+    /// the first node whose `compatible` list contains `compat`.
+    pub fn find_compatible(&self, compat: &str) -> Option<Node<'a>> {
+        self.compatible_nodes(compat).next()
+    }
+
+    /// the first node compatible with any of `compats`, tried in order.
+    // the gic drivers are the only callers, and a pi 3 has no gic
+    #[cfg_attr(feature = "raspi3", allow(dead_code))]
+    pub fn find_any_compatible(&self, compats: &[&str]) -> Option<Node<'a>> {
+        compats.iter().find_map(|compat| self.find_compatible(compat))
+    }
+
+    /// the entries of the memory reservation block, as `(address, size)` pairs.
+    pub fn memory_reservations(&self) -> impl Iterator<Item = (Paddr, u64)> + 'a {
+        let rsvmap = self.mem_rsvmap;
+        (0..).map(move |i| (be64(rsvmap, i * 16), be64(rsvmap, i * 16 + 8)))
+            .take_while(|entry| matches!(entry, (Some(_), Some(size)) if *size != 0))
+            .map(|(addr, size)| (Paddr::from(addr.unwrap()), size.unwrap()))
+    }
+}
+
+/// deepest nesting `DeviceTree::nodes` will descend into. real trees rarely go past 5.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Copy, Clone)]
+/// a node in the structure block.
+pub struct Node<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    name: &'a str,
+    /// offset of the first token after the node name
+    props_start: usize,
+    /// `#address-cells` and `#size-cells` of our parent, which govern our `reg`
+    parent_address_cells: u32,
+    parent_size_cells: u32,
+}
+
+impl<'a> fmt::Debug for Node<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Node({:?})", self.name)
+    }
+}
+
+impl<'a> Node<'a> {
+    /// parse the node whose `FDT_BEGIN_NODE` is at `offset` in `structs`, skipping any
+    /// leading nops.
+    fn parse(
+        structs: &'a [u8],
+        strings: &'a [u8],
+        offset: usize,
+        parent_address_cells: u32,
+        parent_size_cells: u32,
+    ) -> Option<Self> {
+        let offset = skip_nops(structs, offset)?;
+        if be32(structs, offset)? != FDT_BEGIN_NODE {
+            return None;
+        }
+        let (name, len) = cstr(structs.get(offset + 4..)?)?;
+        Some(Node {
+            structs,
+            strings,
+            name: str::from_utf8(name).ok()?,
+            props_start: align4(offset + 4 + len),
+            parent_address_cells,
+            parent_size_cells,
+        })
+    }
+
+    /// the name without its unit address, like `uart`.
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap()
+    }
+
+    fn name_matches(&self, component: &str) -> bool {
+        self.name == component
+            || (!component.contains('@') && self.base_name() == component)
+    }
+
+    pub fn properties(&self) -> Properties<'a> {
+        Properties { node: *self, offset: self.props_start }
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    /// the node's direct children, in order.
+    pub fn children(&self) -> Children<'a> {
+        Children {
+            structs: self.structs,
+            strings: self.strings,
+            offset: end_of_properties(self.structs, self.props_start),
+            address_cells: self.address_cells(),
+            size_cells: self.size_cells(),
+        }
+    }
+
+    /// the `#address-cells` this node imposes on its children.
+    pub fn address_cells(&self) -> u32 {
+        self.property("#address-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(DEFAULT_ADDRESS_CELLS)
+    }
+
+    /// the `#size-cells` this node imposes on its children.
+    pub fn size_cells(&self) -> u32 {
+        self.property("#size-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(DEFAULT_SIZE_CELLS)
+    }
+
+    /// the `compatible` strings, most specific first.
+    pub fn compatible(&self) -> StringList<'a> {
+        self.property("compatible")
+            .map(|p| p.strings())
+            .unwrap_or(StringList { bytes: &[] })
+    }
+
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.compatible().any(|c| c == compat)
+    }
+
+    /// false if the node has a `status` other than `"okay"` or `"ok"`.
+    pub fn is_enabled(&self) -> bool {
+        match self.property("status").and_then(|p| p.as_str()) {
+            None | Some("okay") | Some("ok") => true,
+            Some(_) => false,
+        }
+    }
+
+    /// decode `reg` using the parent's `#address-cells` and `#size-cells`.
+    ///
+    /// the addresses are in the parent's address space, which is only the physical one if
+    /// nothing between the node and the root has a `ranges` that moves them.
+    pub fn reg(&self) -> RegIter<'a> {
+        RegIter {
+            bytes: self.property("reg").map(|p| p.value).unwrap_or(&[]),
+            address_cells: self.parent_address_cells,
+            size_cells: self.parent_size_cells,
+        }
+    }
+}
+
+fn skip_nops(structs: &[u8], mut offset: usize) -> Option<usize> {
+    while be32(structs, offset)? == FDT_NOP {
+        offset += 4;
+    }
+    Some(offset)
+}
+
+/// the offset of the first token after the property list starting at `offset`.
+fn end_of_properties(structs: &[u8], mut offset: usize) -> usize {
+    loop {
+        match be32(structs, offset) {
+            Some(FDT_NOP) => offset += 4,
+            Some(FDT_PROP) => {
+                let len = be32(structs, offset + 4).unwrap_or(0) as usize;
+                offset = align4(offset + 12 + len);
+            }
+            _ => return offset,
+        }
+    }
+}
+
+/// the offset just past the `FDT_END_NODE` matching the `FDT_BEGIN_NODE` at `offset`.
+fn end_of_node(structs: &[u8], offset: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut offset = offset;
+    loop {
+        match be32(structs, offset)? {
+            FDT_BEGIN_NODE => {
+                depth += 1;
+                let (_, len) = cstr(structs.get(offset + 4..)?)?;
+                offset = align4(offset + 4 + len);
+            }
+            FDT_END_NODE => {
+                depth = depth.checked_sub(1)?;
+                offset += 4;
+                if depth == 0 {
+                    return Some(offset);
+                }
+            }
+            FDT_PROP => {
+                let len = be32(structs, offset + 4)? as usize;
+                offset = align4(offset + 12 + len);
+            }
+            FDT_NOP => offset += 4,
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Children<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    /// offset of the next child's `FDT_BEGIN_NODE`, or of our own `FDT_END_NODE`
+    offset: usize,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = Node<'a>;
+    fn next(&mut self) -> Option<Node<'a>> {
+        let start = skip_nops(self.structs, self.offset)?;
+        let child = Node::parse(
+            self.structs, self.strings, start, self.address_cells, self.size_cells,
+        )?;
+        self.offset = end_of_node(self.structs, start)?;
+        Some(child)
+    }
+}
+
+/// a depth-first walk of the tree. see `DeviceTree::nodes`.
+pub struct Nodes<'a> {
+    stack: [Option<Children<'a>>; MAX_DEPTH],
+    depth: usize,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+    fn next(&mut self) -> Option<Node<'a>> {
+        while self.depth > 0 {
+            let top = self.stack[self.depth - 1].as_mut().unwrap();
+            if let Some(node) = top.next() {
+                if self.depth < MAX_DEPTH {
+                    self.stack[self.depth] = Some(node.children());
+                    self.depth += 1;
+                }
+                return Some(node);
+            }
+            self.stack[self.depth - 1] = None;
+            self.depth -= 1;
+        }
+        None
+    }
+}
+
+impl<'a> Node<'a> {
+    /// the root pretends to be its own only child, so that `Nodes` can start from a
+    /// `Children` iterator.
+    fn as_children(&self) -> Children<'a> {
+        Children {
+            structs: self.structs,
+            strings: self.strings,
+            offset: 0,
+            address_cells: self.parent_address_cells,
+            size_cells: self.parent_size_cells,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.value.len() == 4 { be32(self.value, 0) } else { None }
+    }
+
+    /// a `u64`, which may be stored either as one cell or as two.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => be32(self.value, 0).map(u64::from),
+            8 => be64(self.value, 0),
+            _ => None,
+        }
+    }
+
+    /// a single null-terminated string.
+    pub fn as_str(&self) -> Option<&'a str> {
+        let (s, len) = cstr(self.value)?;
+        if len != self.value.len() {
+            return None;
+        }
+        str::from_utf8(s).ok()
+    }
+
+    /// a list of null-terminated strings, like `compatible`.
+    pub fn strings(&self) -> StringList<'a> {
+        StringList { bytes: self.value }
+    }
+}
+
+pub struct Properties<'a> {
+    node: Node<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Properties<'a> {
+    type Item = Property<'a>;
+    fn next(&mut self) -> Option<Property<'a>> {
+        let structs = self.node.structs;
+        let offset = skip_nops(structs, self.offset)?;
+        if be32(structs, offset)? != FDT_PROP {
+            return None;
+        }
+        let len = be32(structs, offset + 4)? as usize;
+        let nameoff = be32(structs, offset + 8)? as usize;
+        let value = structs.get(offset + 12..offset + 12 + len)?;
+        let (name, _) = cstr(self.node.strings.get(nameoff..)?)?;
+        self.offset = align4(offset + 12 + len);
+        Some(Property { name: str::from_utf8(name).ok()?, value })
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct StringList<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for StringList<'a> {
+    type Item = &'a str;
+    fn next(&mut self) -> Option<&'a str> {
+        let (s, len) = cstr(self.bytes)?;
+        self.bytes = &self.bytes[len..];
+        str::from_utf8(s).ok()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// one `(address, size)` pair out of a `reg` property.
+pub struct RegEntry {
+    pub address: u64,
+    pub size: u64,
+}
+
+pub struct RegIter<'a> {
+    bytes: &'a [u8],
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for RegIter<'a> {
+    type Item = RegEntry;
+    fn next(&mut self) -> Option<RegEntry> {
+        // the cell counts come straight from the tree, so they can be anything
+        let entry_len = self.address_cells.checked_add(self.size_cells)?.checked_mul(4)? as usize;
+        if entry_len == 0 || self.bytes.len() < entry_len {
+            return None;
+        }
+        let address = read_cells(self.bytes, self.address_cells)?;
+        let size = read_cells(&self.bytes[4 * self.address_cells as usize..], self.size_cells)?;
+        self.bytes = &self.bytes[entry_len..];
+        Some(RegEntry { address, size })
+    }
+}
+
+static DEVICE_TREE: Once<DeviceTree<'static>> = Once::new();
+
+/// validate the dtb at `dtb` and make it available through `device_tree`.
+///
+/// # safety
+///
+/// the blob must stay where it is and unmodified forever after, which means the frame
+/// allocator must never hand it out.
+pub unsafe fn init(dtb: Paddr) -> Result<&'static DeviceTree<'static>, FdtError> {
+    let tree = DeviceTree::from_paddr(dtb)?;
+    Ok(DEVICE_TREE.call_once(|| tree))
+}
+
+/// the device tree we booted with, if we got a valid one.
+pub fn device_tree() -> Option<&'static DeviceTree<'static>> {
+    DEVICE_TREE.get()
+}
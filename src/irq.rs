@@ -0,0 +1,155 @@
+//! interrupt dispatch.
+//!
+//! the board picks an interrupt controller at boot. when an irq arrives, we ask the
+//! controller which one it was, call the handler registered for that number, and tell
+//! the controller we're done. numbering is up to the controller, but every controller
+//! we have puts software-generated interrupts at 0..16 and per-core interrupts at
+//! 16..32, the way the gic does.
+
+use crate::exception::{self, TrapFrame};
+use crate::percpu::Counters;
+use crate::{percpu, println};
+use cortex_a::registers::DAIF;
+use spin::{Mutex, Once, RwLock};
+use tock_registers::interfaces::{Readable, Writeable};
+
+pub type Irq = u32;
+
+/// one more than the largest irq number any controller may hand us.
+pub const MAX_IRQS: usize = 1024;
+
+/// the priority irqs get unless someone asks for something else. lower is more urgent.
+pub const DEFAULT_PRIORITY: u8 = 0xa0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// who a software-generated interrupt goes to.
+pub enum SgiTarget {
+    /// every core but the one sending it.
+    AllOthers,
+}
+
+/// sgis and ppis belong to one core each, so `enable`, `disable` and `set_priority`
+/// on an irq below 32 only affect the calling core.
+pub trait InterruptController: Send {
+    /// set up the calling core's view of the controller. called once on every core,
+    /// before that core unmasks irqs.
+    fn init_cpu(&mut self);
+    fn enable(&mut self, irq: Irq);
+    fn disable(&mut self, irq: Irq);
+    fn set_priority(&mut self, irq: Irq, priority: u8);
+    /// route a shared interrupt to the core with index `core`.
+    fn set_target(&mut self, irq: Irq, core: usize);
+    /// claim the highest priority pending irq for the calling core, or `None` if
+    /// there's nothing (left) to do.
+    fn acknowledge(&mut self) -> Option<Irq>;
+    fn end_of_interrupt(&mut self, irq: Irq);
+    /// raise software-generated interrupt `sgi`, which must be less than 16.
+    fn send_sgi(&mut self, sgi: Irq, target: SgiTarget);
+}
+
+pub type Handler = fn(Irq);
+
+static CONTROLLER: Once<&'static Mutex<dyn InterruptController>> = Once::new();
+
+static HANDLERS: RwLock<[Option<Handler>; MAX_IRQS]> = RwLock::new([None; MAX_IRQS]);
+
+fn controller() -> &'static Mutex<dyn InterruptController> {
+    *CONTROLLER.get().expect("no interrupt controller")
+}
+
+/// run `f` with irqs masked on this core, so that it can take locks an irq handler
+/// might also take.
+///
+/// irq handlers can allocate, so the locks behind the heap, the slab caches and the frame
+/// allocator are only ever taken in here. otherwise an irq arriving while one of them is
+/// held would spin on it forever.
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let daif = DAIF.get();
+    disable_local();
+    let ret = f();
+    DAIF.set(daif);
+    ret
+}
+
+/// unmask irqs on this core.
+pub fn enable_local() { unsafe {
+    asm!("msr daifclr, #2", options(nostack));
+} }
+
+/// mask irqs on this core.
+pub fn disable_local() { unsafe {
+    asm!("msr daifset, #2", options(nostack));
+} }
+
+/// dispatch irqs through `controller` from now on, and set up the calling core's
+/// interface to it.
+pub fn init(controller: &'static Mutex<dyn InterruptController>) {
+    CONTROLLER.call_once(|| controller);
+    exception::set_irq_handler(handle_irq);
+    init_cpu();
+}
+
+/// set up the calling core's interface to the interrupt controller. `init` does this
+/// for the boot core; the others have to call it themselves.
+pub fn init_cpu() {
+    without_interrupts(|| controller().lock().init_cpu());
+}
+
+/// true once the board has handed us an interrupt controller.
+pub fn is_initialized() -> bool {
+    CONTROLLER.get().is_some()
+}
+
+/// call `handler` whenever `irq` fires, and enable it. a shared interrupt is routed to
+/// the calling core; an sgi or ppi is only enabled on the calling core.
+pub fn register_handler(irq: Irq, handler: Handler) {
+    without_interrupts(|| {
+        HANDLERS.write()[irq as usize] = Some(handler);
+        let mut c = controller().lock();
+        c.set_priority(irq, DEFAULT_PRIORITY);
+        if irq >= 32 {
+            c.set_target(irq, percpu::this().core_id());
+        }
+        c.enable(irq);
+    });
+}
+
+pub fn enable(irq: Irq) {
+    without_interrupts(|| controller().lock().enable(irq));
+}
+
+pub fn set_priority(irq: Irq, priority: u8) {
+    without_interrupts(|| controller().lock().set_priority(irq, priority));
+}
+
+pub fn send_sgi(sgi: Irq, target: SgiTarget) {
+    assert!(sgi < 16, "sgi {} out of range", sgi);
+    without_interrupts(|| controller().lock().send_sgi(sgi, target));
+}
+
+/// the irq vector lands here, with irqs masked.
+fn handle_irq(_frame: &mut TrapFrame) {
+    let percpu = percpu::this();
+    percpu.irq_depth.set(percpu.irq_depth.get() + 1);
+
+    // keep going until the controller runs dry, so that a burst of irqs costs one
+    // exception rather than one each.
+    loop {
+        // not in a `while let`, which would hold the lock for the whole body
+        let irq = match controller().lock().acknowledge() {
+            Some(irq) => irq,
+            None => break,
+        };
+        Counters::bump(percpu!(counters.irqs));
+        let handler = HANDLERS.read()[irq as usize];
+        if let Some(handler) = handler {
+            handler(irq);
+        } else {
+            println!("disabling irq {}, which has no handler", irq);
+            controller().lock().disable(irq);
+        }
+        controller().lock().end_of_interrupt(irq);
+    }
+
+    percpu.irq_depth.set(percpu.irq_depth.get() - 1);
+}
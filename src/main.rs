@@ -3,12 +3,16 @@
 #![no_main]
 #![feature(
     asm,
+    global_asm,
     naked_functions,
     format_args_nl,
     panic_info_message,
     const_panic,
+    alloc_error_handler,
 )]
 
+extern crate alloc;
+
 #[cfg(feature = "virt")]
 #[path = "board/virt/mod.rs"]
 mod board;
@@ -25,9 +29,21 @@ mod asm;
 mod boot;
 mod console;
 mod driver;
+mod exception;
+mod fdt;
+mod irq;
 mod memory;
-
+mod percpu;
+mod psci;
+mod ringbuf;
+mod smp;
+mod time;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::convert::From;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -35,7 +51,12 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         console::force_unlock_console();
     }
 
-    let _ = console::with_writing(|c| {
+    // the irq handler might never get to run again, so don't leave output in a buffer
+    // waiting for it
+    console::with_console(|c| c.disable_interrupts());
+
+    // a wedged uart shouldn't stop us getting to `sleep_forever`
+    let _ = console::with_writing_timeout(console::WEDGED_TIMEOUT, |c| {
         c.write_str("\nKernel panic")?;
 
         if let Some(msg) = info.message() {
@@ -50,6 +71,8 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         c.write_str("\n")
     });
 
+    // after the message, in case the interrupt controller's lock is what we panicked with
+    smp::stop_others();
     sleep_forever()
 }
 
@@ -59,11 +82,18 @@ fn sleep_forever() -> ! {
 }
 
 fn echo_loop() -> ! {
-    console::with_console(|c| {
-        loop {
-            let byte = c.blocking_read_byte();
-            c.blocking_write_byte(byte);
-        } } )
+    loop {
+        match console::read_byte() {
+            Ok(byte) => {
+                // if the console's wedged there's nowhere to complain to anyway
+                let _ = console::write_byte(byte);
+            }
+            Err(e) => {
+                let errors = console::with_console(|c| c.line_errors());
+                println!("\n[{}; so far {}]", e, errors);
+            }
+        }
+    }
 }
 
 fn core_0_main() -> ! {
@@ -76,29 +106,84 @@ fn core_0_main() -> ! {
     
     if memory::in_kaddr_space(pc) {
         let pc = memory::Kaddr::from(pc);
-        panic!(
-            "We're in the high address space at {:x} when we should be in the low address space at {:x}!",
+        println!("We are in the high address space, and all is well with the world.");
+        println!(
+            "PC is currently {:x}, which is {:x} in the low address space",
             pc, memory::kaddr_to_paddr(pc),
         );
     } else {
         let pc = memory::Paddr::from(pc);
-        println!("We are in the low address space, and all is well with the world.");
-        println!(
-            "PC is currently {:x}, which would be {:x} in the high address space",
+        panic!(
+            "We're in the low address space at {:x} when we should be in the high address space at {:x}!",
             pc, memory::paddr_to_kaddr(pc),
         );
     }
 
     println!("kernel ends at {:x}", memory::kernel_end());
+    println!("the system counter runs at {} Hz; it's been {}s since reset", time::frequency(), time::Instant::now());
+
+    let start = time::Instant::now();
+    time::sleep_for(Duration::from_millis(10));
+    println!("sleeping for 10ms took {:?}", start.elapsed());
+
+    if irq::is_initialized() {
+        static TICKS: AtomicUsize = AtomicUsize::new(0);
+        let start = time::Instant::now();
+        time::set_periodic(Duration::from_millis(1), || { TICKS.fetch_add(1, Ordering::Relaxed); });
+        let ticked = time::block_for(
+            || TICKS.load(Ordering::Relaxed) >= 10, Duration::from_secs(1), time::Wait::Event,
+        );
+        time::cancel_timer();
+        let ticks = TICKS.load(Ordering::Relaxed);
+        match ticked {
+            Ok(()) => {
+                println!("ten ticks of a 1ms periodic timer took {:?}", start.elapsed());
+            }
+            Err(e) => {
+                println!("the periodic timer only ticked {} times: {}", ticks, e);
+            }
+        }
+        time::set_oneshot(Duration::from_millis(1), || {
+            println!("and a one-shot timer went off");
+        });
+        time::sleep_for(Duration::from_millis(10));
+    }
+
+    memory::framealloc::print_layout();
+    println!("highest physical address is {:x}", memory::max_phys_addr());
 
     if let Some(block) = memory::framealloc::alloc_frame(memory::PAGE_SIZE) {
         println!("Successfully allocated the block {:x}", block);
+        if let Some(info) = memory::framealloc::frame_info(block) {
+            println!("The frame table says it's {:?}", info);
+        }
         unsafe { memory::framealloc::free_frame(block, memory::PAGE_SIZE) };
         println!("Successfully freed that block.");
     } else {
         println!("Failed to alloc a block!");
     }
+
+    let squares: Vec<u64> = (0..16).map(|i| i * i).collect();
+    let boxed = Box::new(squares.iter().sum::<u64>());
+    println!("the first {} squares add up to {}, from the heap", squares.len(), boxed);
+    drop(squares);
+    memory::heap::print_stats();
+
+    let objects: Vec<_> = (0..100).filter_map(|_| memory::slab::alloc(48)).collect();
+    println!("allocated {} 48-byte objects from a slab cache", objects.len());
+    for &object in objects.iter().step_by(2) {
+        unsafe { memory::slab::free(object, 48) };
+    }
+    memory::slab::print_stats();
+
+    let text = memory::Vaddr::from(u64::from(memory::text_start()));
+    let mapped = memory::paging::kernel_space().lock().translate(text);
+    println!("the kernel's tables map its text at {:x} to {:x?}", text, mapped);
+    memory::framealloc::print_stats();
     
+    smp::start_secondaries(fdt::device_tree());
+    println!("{} cores online", smp::cores_online());
+
     println!("Now echoing:");
 
     echo_loop()
@@ -1,7 +1,15 @@
 use crate::asm::{dsb};
+use crate::board;
+use crate::fdt::DeviceTree;
 use core::ops::RangeInclusive;
+use physmap::Region;
 
 pub mod framealloc;
+pub mod heap;
+pub mod addrspace;
+pub mod paging;
+pub mod physmap;
+pub mod slab;
 
 // These are all defined in `/link.ld`
 extern "C" {
@@ -29,10 +37,13 @@ const KADDR_MIN: u64 = 0xffff_0000_0000_0000;
 const KADDR_MAX: u64 = 0xffff_ffff_ffff_ffff;
 const KADDR_SPACE: RangeInclusive<u64> = KADDR_MIN ..= KADDR_MAX;
 
-pub use crate::board::memory::MEM_SIZE;
+/// how much of the stack which grows down from `__text_start` at boot we protect from
+/// the frame allocator.
+pub const BOOT_STACK_SIZE: u64 = 16 * PAGE_SIZE;
 
-pub fn kernel_end() -> Paddr { Paddr(unsafe {&__kernel_end as *const u64 as u64}) }
-pub fn max_phys_addr() -> Paddr { Paddr(MEM_SIZE - 1) }
+pub fn text_start() -> Paddr { kernel_paddr(unsafe {&__text_start as *const u64 as u64}) }
+pub fn kernel_end() -> Paddr { kernel_paddr(unsafe {&__kernel_end as *const u64 as u64}) }
+pub fn max_phys_addr() -> Paddr { physmap::max_phys_addr() }
 
 pub unsafe trait Pointer: Sized {
     fn as_const<T>(self) -> *const T;
@@ -50,12 +61,13 @@ pub unsafe trait Pointer: Sized {
 /// a physical address
 pub struct Paddr(u64);
 
+/// goes through the linear map, so only meaningful once `paging` has turned the mmu on.
 unsafe impl Pointer for Paddr {
     fn as_const<T>(self) -> *const T {
-        self.0 as *const T
+        paddr_to_kaddr(self).as_const()
     }
     fn as_mut<T>(self) -> *mut T {
-        self.0 as *mut T
+        paddr_to_kaddr(self).as_mut()
     }
 }
 
@@ -69,12 +81,13 @@ pub struct Vaddr(u64);
 /// a kernel vertial address, in the range `KADDR_MIN..=KADDR_MAX`
 pub struct Kaddr(u64);
 
+/// only meaningful once `paging` has turned the mmu on.
 unsafe impl Pointer for Kaddr {
     fn as_const<T>(self) -> *const T {
-        (self.0 - KADDR_MIN) as *const T
+        self.0 as *const T
     }
     fn as_mut<T>(self) -> *mut T {
-        (self.0 - KADDR_MIN) as *mut T
+        self.0 as *mut T
     }
 }
 
@@ -124,6 +137,22 @@ pub const fn kaddr_to_paddr(Kaddr(kaddr): Kaddr) -> Paddr {
     Paddr(kaddr - KADDR_MIN)
 }
 
+/// where the mmio at `paddr` shows up in the linear map, for drivers which want a raw
+/// pointer to their registers.
+pub const fn mmio(paddr: u64) -> *mut u8 {
+    (paddr + KADDR_MIN) as *mut u8
+}
+
+/// the physical address of `addr`, which is somewhere in the kernel image, whichever
+/// half we're running in.
+pub fn kernel_paddr(addr: u64) -> Paddr {
+    if in_kaddr_space(addr) {
+        kaddr_to_paddr(Kaddr(addr))
+    } else {
+        Paddr(addr)
+    }
+}
+
 pub unsafe fn init_data() {
     r0::zero_bss(&mut __bss_start, &mut __bss_end);
     r0::init_data(&mut __data_start, &mut __data_end, &__data_loadaddr);
@@ -131,3 +160,53 @@ pub unsafe fn init_data() {
     // ensure all writes are seen by the whole system
     dsb::sy();
 }
+
+/// tell the frame allocator about everything it mustn't hand out that we know of at
+/// boot: the kernel image and its stack, the device tree and whatever the device tree
+/// says is reserved, and the board's own carve-outs.
+pub fn reserve_boot_regions(tree: Option<&DeviceTree>) {
+    use framealloc::reserve;
+
+    reserve(Region {
+        start: Paddr(text_start().0 - BOOT_STACK_SIZE),
+        end: kernel_end(),
+    });
+
+    for &(start, size) in board::memory::RESERVED {
+        reserve(Region::new(Paddr(start), size));
+    }
+
+    let tree = if let Some(tree) = tree { tree } else { return };
+
+    reserve(Region::new(tree.paddr(), tree.total_size()));
+
+    for (start, size) in tree.memory_reservations() {
+        reserve(Region::new(start, size));
+    }
+
+    if let Some(reserved_memory) = tree.find_node("/reserved-memory") {
+        for node in reserved_memory.children().filter(|n| n.is_enabled()) {
+            for reg in node.reg() {
+                reserve(Region::new(Paddr(reg.address), reg.size));
+            }
+        }
+    }
+
+    if let Some(chosen) = tree.find_node("/chosen") {
+        let initrd_start = chosen.property("linux,initrd-start").and_then(|p| p.as_u64());
+        let initrd_end = chosen.property("linux,initrd-end").and_then(|p| p.as_u64());
+        if let (Some(start), Some(end)) = (initrd_start, initrd_end) {
+            reserve(Region { start: Paddr(start), end: Paddr(end) });
+        }
+    }
+
+    // cores parked by the firmware spin on their release address, so that has to stay
+    // put until they're released.
+    if let Some(cpus) = tree.find_node("/cpus") {
+        for cpu in cpus.children() {
+            if let Some(addr) = cpu.property("cpu-release-addr").and_then(|p| p.as_u64()) {
+                reserve(Region::new(Paddr(addr), 8));
+            }
+        }
+    }
+}
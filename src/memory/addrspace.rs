@@ -0,0 +1,628 @@
+//! address spaces: a tree of aarch64 translation tables, and the mappings in it.
+//!
+//! we use a 4 KiB granule and 48-bit virtual addresses, so every table is one page of
+//! 512 entries and a walk takes up to four levels. mappings use the biggest blocks that
+//! fit, so 1 GiB at level 1 and 2 MiB at level 2, and blocks are split into smaller
+//! pieces when only part of one is unmapped or protected, unless the address space
+//! forbids it. tables come from `framealloc`, and go back to it once they're empty.
+
+use crate::memory::framealloc::{self, FrameState, NO_OWNER};
+use crate::memory::{Paddr, Pointer, Vaddr, KADDR_MIN, PAGE_SIZE, VADDR_MAX};
+use core::fmt;
+use tock_registers::{register_bitfields, LocalRegisterCopy};
+
+/// entries in a table.
+pub const ENTRIES: usize = 512;
+
+/// the level of the root table, whose entries each cover 512 GiB.
+const ROOT_LEVEL: usize = 0;
+
+/// the level whose entries are single pages.
+const PAGE_LEVEL: usize = 3;
+
+/// indices into `MAIR_EL1`, which `paging` sets up to match.
+pub const MAIR_NORMAL: u64 = 0;
+pub const MAIR_DEVICE: u64 = 1;
+
+register_bitfields! {u64,
+    // a stage 1 table, block or page descriptor, for a 4 KiB granule
+    DESCRIPTOR [
+        valid OFFSET(0) NUMBITS(1) [],
+        // set for a table at levels 0 to 2, or clear for a block. set for a page at
+        // level 3, where there are no blocks
+        table OFFSET(1) NUMBITS(1) [],
+        attr_index OFFSET(2) NUMBITS(3) [],
+        access OFFSET(6) NUMBITS(2) [
+            KernelReadWrite = 0b00,
+            ReadWrite = 0b01,
+            KernelReadOnly = 0b10,
+            ReadOnly = 0b11
+        ],
+        shareability OFFSET(8) NUMBITS(2) [
+            NonShareable = 0b00,
+            Outer = 0b10,
+            Inner = 0b11
+        ],
+        access_flag OFFSET(10) NUMBITS(1) [],
+        not_global OFFSET(11) NUMBITS(1) [],
+        // bits 47:12 of the output address, or of the next table's address
+        address OFFSET(12) NUMBITS(36) [],
+        kernel_no_execute OFFSET(53) NUMBITS(1) [],
+        user_no_execute OFFSET(54) NUMBITS(1) []
+    ]
+}
+
+type Descriptor = LocalRegisterCopy<u64, DESCRIPTOR::Register>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// what's behind a mapping.
+pub enum MemoryKind {
+    /// ordinary write-back cacheable ram.
+    Normal,
+    /// mmio, as device-ngnre: uncached, with accesses neither merged nor reordered.
+    /// never executable, whatever `Attributes::executable` says.
+    Device,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// what a mapping is, and who can do what with it. the kernel can always read it.
+pub struct Attributes {
+    pub kind: MemoryKind,
+    pub writable: bool,
+    /// by the kernel if `user` is false, or by userspace if it's true. the kernel never
+    /// executes userspace's memory.
+    pub executable: bool,
+    /// whether userspace can get at it too.
+    pub user: bool,
+}
+
+impl Attributes {
+    /// the kernel's own ram.
+    pub const KERNEL: Attributes = Attributes {
+        kind: MemoryKind::Normal,
+        writable: true,
+        executable: true,
+        user: false,
+    };
+
+    /// mmio, for the kernel's drivers.
+    pub const DEVICE: Attributes = Attributes {
+        kind: MemoryKind::Device,
+        writable: true,
+        executable: false,
+        user: false,
+    };
+
+    pub const USER_DATA: Attributes = Attributes {
+        kind: MemoryKind::Normal,
+        writable: true,
+        executable: false,
+        user: true,
+    };
+
+    pub const USER_CODE: Attributes = Attributes {
+        kind: MemoryKind::Normal,
+        writable: false,
+        executable: true,
+        user: true,
+    };
+
+    /// everything in a leaf descriptor but its address and type.
+    fn descriptor_bits(self) -> u64 {
+        let kind = match self.kind {
+            MemoryKind::Normal => {
+                DESCRIPTOR::attr_index.val(MAIR_NORMAL) + DESCRIPTOR::shareability::Inner
+            }
+            MemoryKind::Device => {
+                DESCRIPTOR::attr_index.val(MAIR_DEVICE) + DESCRIPTOR::shareability::Outer
+            }
+        };
+        let access = match (self.writable, self.user) {
+            (true, false) => DESCRIPTOR::access::KernelReadWrite,
+            (true, true) => DESCRIPTOR::access::ReadWrite,
+            (false, false) => DESCRIPTOR::access::KernelReadOnly,
+            (false, true) => DESCRIPTOR::access::ReadOnly,
+        };
+        let executable = self.executable && self.kind == MemoryKind::Normal;
+        let kernel_no_execute = if executable && !self.user {
+            DESCRIPTOR::kernel_no_execute::CLEAR
+        } else {
+            DESCRIPTOR::kernel_no_execute::SET
+        };
+        let user_no_execute = if executable && self.user {
+            DESCRIPTOR::user_no_execute::CLEAR
+        } else {
+            DESCRIPTOR::user_no_execute::SET
+        };
+        // userspace's mappings belong to one address space, so they mustn't outlive a
+        // switch to another in the tlb
+        let not_global = if self.user {
+            DESCRIPTOR::not_global::SET
+        } else {
+            DESCRIPTOR::not_global::CLEAR
+        };
+        (kind + access + kernel_no_execute + user_no_execute + not_global
+            + DESCRIPTOR::valid::SET + DESCRIPTOR::access_flag::SET).value
+    }
+
+    fn from_descriptor(descriptor: Descriptor) -> Attributes {
+        let kind = if descriptor.read(DESCRIPTOR::attr_index) == MAIR_DEVICE {
+            MemoryKind::Device
+        } else {
+            MemoryKind::Normal
+        };
+        let (writable, user) = match descriptor.read_as_enum(DESCRIPTOR::access) {
+            Some(DESCRIPTOR::access::Value::KernelReadWrite) => (true, false),
+            Some(DESCRIPTOR::access::Value::ReadWrite) => (true, true),
+            Some(DESCRIPTOR::access::Value::KernelReadOnly) => (false, false),
+            Some(DESCRIPTOR::access::Value::ReadOnly) | None => (false, true),
+        };
+        let executable = if user {
+            !descriptor.is_set(DESCRIPTOR::user_no_execute)
+        } else {
+            !descriptor.is_set(DESCRIPTOR::kernel_no_execute)
+        };
+        Attributes { kind, writable, executable, user }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// an address or size wasn't a whole number of pages.
+    Misaligned,
+    /// the range runs off the end of the address space.
+    OutOfRange,
+    /// something in the range is mapped already, starting at this address.
+    AlreadyMapped(Vaddr),
+    /// something in the range isn't mapped, starting at this address.
+    NotMapped(Vaddr),
+    /// there were no frames left for a table.
+    OutOfMemory,
+    /// the block mapping this address would have to be split, which this address space
+    /// doesn't allow.
+    WouldSplit(Vaddr),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Misaligned => write!(f, "not page aligned"),
+            MapError::OutOfRange => write!(f, "past the end of the address space"),
+            MapError::AlreadyMapped(vaddr) => write!(f, "{:x} is already mapped", vaddr),
+            MapError::NotMapped(vaddr) => write!(f, "{:x} isn't mapped", vaddr),
+            MapError::OutOfMemory => write!(f, "out of memory for translation tables"),
+            MapError::WouldSplit(vaddr) => write!(f, "the block at {:x} can't be split", vaddr),
+        }
+    }
+}
+
+/// the bytes covered by one entry of a table at `level`.
+const fn entry_size(level: usize) -> u64 {
+    1 << (12 + 9 * (PAGE_LEVEL - level))
+}
+
+/// the index of `vaddr`'s entry in a table at `level`.
+fn entry_index(vaddr: u64, level: usize) -> usize {
+    (vaddr / entry_size(level)) as usize % ENTRIES
+}
+
+/// the entries of the table at `table`.
+///
+/// unsafe because nothing stops two of these existing for the same table.
+unsafe fn entries<'a>(table: Paddr) -> &'a mut [u64; ENTRIES] {
+    &mut *table.as_mut()
+}
+
+fn descriptor_address(descriptor: Descriptor) -> Paddr {
+    Paddr(descriptor.read(DESCRIPTOR::address) << 12)
+}
+
+/// whether `descriptor`, from a table at `level`, maps memory rather than pointing to
+/// another table.
+fn is_leaf(descriptor: Descriptor, level: usize) -> bool {
+    descriptor.is_set(DESCRIPTOR::valid)
+        && (level == PAGE_LEVEL || !descriptor.is_set(DESCRIPTOR::table))
+}
+
+fn leaf_descriptor(paddr: Paddr, level: usize, bits: u64) -> u64 {
+    let table = if level == PAGE_LEVEL {
+        DESCRIPTOR::table::SET
+    } else {
+        DESCRIPTOR::table::CLEAR
+    };
+    bits | (table + DESCRIPTOR::address.val(paddr.0 >> 12)).value
+}
+
+/// a descriptor pointing at the next level's table at `table`.
+pub fn table_descriptor(table: Paddr) -> u64 {
+    (DESCRIPTOR::valid::SET
+        + DESCRIPTOR::table::SET
+        + DESCRIPTOR::address.val(table.0 >> 12)).value
+}
+
+/// a descriptor mapping the block or page at `paddr` with `attrs`, from a table at
+/// `level`, for tables built by hand rather than through an `AddressSpace`.
+pub fn block_descriptor(paddr: Paddr, level: usize, attrs: Attributes) -> u64 {
+    leaf_descriptor(paddr, level, attrs.descriptor_bits())
+}
+
+/// a zeroed page to be a table.
+fn alloc_table() -> Result<Paddr, MapError> {
+    let table = framealloc::alloc_frame_for(PAGE_SIZE, FrameState::PageTable, NO_OWNER)
+        .ok_or(MapError::OutOfMemory)?;
+    unsafe { core::ptr::write_bytes(table.as_mut::<u64>(), 0, ENTRIES) };
+    Ok(table)
+}
+
+/// make sure the table walker sees what we've written to the tables.
+fn publish() {
+    unsafe { asm!("dsb ishst", "isb", options(nostack)) };
+}
+
+/// throw away every core's cached translations for the page at `vaddr`, in every
+/// address space. the kernel's tables are walked through `TTBR1_EL1`, where what they
+/// map at `vaddr` shows up at `KADDR_MIN + vaddr`, so this does the high alias too.
+fn invalidate(vaddr: u64) {
+    // the operand is bits 55:12 of the address
+    let low = (vaddr >> 12) & 0xfff_ffff_ffff;
+    let high = ((vaddr | KADDR_MIN) >> 12) & 0xfff_ffff_ffff;
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {low}",
+            "tlbi vaae1is, {high}",
+            "dsb ish",
+            "isb",
+            low = in(reg) low,
+            high = in(reg) high,
+            options(nostack),
+        );
+    }
+}
+
+/// replace the entry at `entry`, which maps memory, with `new`, the way the
+/// architecture wants: invalidate it everywhere first, so no core ever sees both.
+unsafe fn break_before_make(entry: &mut u64, vaddr: u64, new: u64) {
+    *entry = 0;
+    invalidate(vaddr);
+    *entry = new;
+    publish();
+}
+
+/// replace the block at `entry`, which maps `vaddr` at `level`, with a table of the next
+/// level mapping the same memory the same way, and return that table.
+unsafe fn split_block(entry: &mut u64, vaddr: u64, level: usize) -> Result<Paddr, MapError> {
+    let table = alloc_table()?;
+    let descriptor = Descriptor::new(*entry);
+    let bits = *entry & !(DESCRIPTOR::table::SET + DESCRIPTOR::address::SET).mask();
+    let start = descriptor_address(descriptor);
+    let child_size = entry_size(level + 1);
+    for (i, child) in entries(table).iter_mut().enumerate() {
+        *child = leaf_descriptor(Paddr(start.0 + i as u64 * child_size), level + 1, bits);
+    }
+    break_before_make(entry, vaddr & !(entry_size(level) - 1), table_descriptor(table));
+    Ok(table)
+}
+
+/// call `f` on the entry for each piece of `vaddr..vaddr + size` in the table at `table`,
+/// which is at `level`, along with the piece's address and size.
+unsafe fn for_each_entry<F>(table: Paddr, level: usize, vaddr: u64, size: u64, mut f: F)
+    -> Result<(), MapError>
+where
+    F: FnMut(&mut u64, u64, u64) -> Result<(), MapError>,
+{
+    let entry_size = entry_size(level);
+    let end = vaddr + size;
+    let mut vaddr = vaddr;
+    while vaddr < end {
+        let chunk = (end - vaddr).min(entry_size - (vaddr & (entry_size - 1)));
+        f(&mut entries(table)[entry_index(vaddr, level)], vaddr, chunk)?;
+        vaddr += chunk;
+    }
+    Ok(())
+}
+
+/// map `vaddr..vaddr + size` to `paddr` onwards in the table at `table`, which is at
+/// `level`, adding to `mapped` as it goes.
+unsafe fn map_range(
+    table: Paddr,
+    level: usize,
+    vaddr: u64,
+    paddr: Paddr,
+    size: u64,
+    bits: u64,
+    mapped: &mut u64,
+) -> Result<(), MapError> {
+    let entry_size = entry_size(level);
+    for_each_entry(table, level, vaddr, size, |entry, piece, chunk| {
+        let descriptor = Descriptor::new(*entry);
+        if is_leaf(descriptor, level) {
+            return Err(MapError::AlreadyMapped(Vaddr(piece)));
+        }
+        let paddr = Paddr(paddr.0 + (piece - vaddr));
+        // there are no blocks at level 0
+        let whole_entry = chunk == entry_size && paddr.0 & (entry_size - 1) == 0;
+        if level != ROOT_LEVEL && whole_entry && !descriptor.is_set(DESCRIPTOR::valid) {
+            *entry = leaf_descriptor(paddr, level, bits);
+            *mapped += chunk;
+            return Ok(());
+        }
+        let next = if descriptor.is_set(DESCRIPTOR::valid) {
+            descriptor_address(descriptor)
+        } else {
+            let next = alloc_table()?;
+            *entry = table_descriptor(next);
+            next
+        };
+        map_range(next, level + 1, piece, paddr, chunk, bits, mapped)
+    })
+}
+
+/// unmap whatever's mapped in `vaddr..vaddr + size` in the table at `table`, which is at
+/// `level`, freeing any tables below it which end up empty.
+unsafe fn unmap_range(table: Paddr, level: usize, vaddr: u64, size: u64) -> Result<(), MapError> {
+    let entry_size = entry_size(level);
+    for_each_entry(table, level, vaddr, size, |entry, piece, chunk| {
+        let descriptor = Descriptor::new(*entry);
+        if !descriptor.is_set(DESCRIPTOR::valid) {
+            return Ok(());
+        }
+        if is_leaf(descriptor, level) && chunk == entry_size {
+            *entry = 0;
+            invalidate(piece);
+            return Ok(());
+        }
+        let next = if is_leaf(descriptor, level) {
+            split_block(entry, piece, level)?
+        } else {
+            descriptor_address(descriptor)
+        };
+        unmap_range(next, level + 1, piece, chunk)?;
+        if entries(next).iter().all(|&e| e == 0) {
+            *entry = 0;
+            // the walker might have the table cached, so it has to go before we free it
+            invalidate(piece);
+            framealloc::free_frame(next, PAGE_SIZE);
+        }
+        Ok(())
+    })
+}
+
+/// free any tables below the table at `table`, which is at `level`, in `vaddr..vaddr +
+/// size` which have nothing in them, without unmapping anything.
+unsafe fn prune_range(table: Paddr, level: usize, vaddr: u64, size: u64) {
+    let _ = for_each_entry(table, level, vaddr, size, |entry, piece, chunk| {
+        let descriptor = Descriptor::new(*entry);
+        if !descriptor.is_set(DESCRIPTOR::valid) || is_leaf(descriptor, level) {
+            return Ok(());
+        }
+        let next = descriptor_address(descriptor);
+        prune_range(next, level + 1, piece, chunk);
+        if entries(next).iter().all(|&e| e == 0) {
+            *entry = 0;
+            invalidate(piece);
+            framealloc::free_frame(next, PAGE_SIZE);
+        }
+        Ok(())
+    });
+}
+
+/// give everything in `vaddr..vaddr + size`, in the table at `table`, which is at
+/// `level`, the attributes in `bits`. it all has to be mapped.
+unsafe fn protect_range(table: Paddr, level: usize, vaddr: u64, size: u64, bits: u64)
+    -> Result<(), MapError>
+{
+    let entry_size = entry_size(level);
+    for_each_entry(table, level, vaddr, size, |entry, piece, chunk| {
+        let descriptor = Descriptor::new(*entry);
+        if is_leaf(descriptor, level) && chunk == entry_size {
+            let new = leaf_descriptor(descriptor_address(descriptor), level, bits);
+            let old_kind = descriptor.read(DESCRIPTOR::attr_index);
+            let new_kind = Descriptor::new(new).read(DESCRIPTOR::attr_index);
+            // permissions can change in place, but a memory type can't
+            if old_kind == new_kind {
+                *entry = new;
+                invalidate(piece);
+            } else {
+                break_before_make(entry, piece, new);
+            }
+            return Ok(());
+        }
+        let next = if is_leaf(descriptor, level) {
+            split_block(entry, piece, level)?
+        } else {
+            descriptor_address(descriptor)
+        };
+        protect_range(next, level + 1, piece, chunk, bits)
+    })
+}
+
+/// fail unless everything in `vaddr..vaddr + size`, in the table at `table`, which is at
+/// `level`, is mapped.
+unsafe fn check_mapped(table: Paddr, level: usize, vaddr: u64, size: u64) -> Result<(), MapError> {
+    for_each_entry(table, level, vaddr, size, |entry, piece, chunk| {
+        let descriptor = Descriptor::new(*entry);
+        if !descriptor.is_set(DESCRIPTOR::valid) {
+            Err(MapError::NotMapped(Vaddr(piece)))
+        } else if is_leaf(descriptor, level) {
+            Ok(())
+        } else {
+            check_mapped(descriptor_address(descriptor), level + 1, piece, chunk)
+        }
+    })
+}
+
+/// free the table at `table`, which is at `level`, and every table below it.
+unsafe fn free_tables(table: Paddr, level: usize) {
+    for &entry in entries(table).iter() {
+        let descriptor = Descriptor::new(entry);
+        if descriptor.is_set(DESCRIPTOR::valid) && !is_leaf(descriptor, level) {
+            free_tables(descriptor_address(descriptor), level + 1);
+        }
+    }
+    framealloc::free_frame(table, PAGE_SIZE);
+}
+
+/// a tree of translation tables. dropping it frees the tables, but not whatever they
+/// map.
+pub struct AddressSpace {
+    root: Paddr,
+    /// whether `unmap` and `protect` may split blocks.
+    splittable: bool,
+}
+
+unsafe impl Send for AddressSpace {}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, MapError> {
+        Ok(AddressSpace { root: alloc_table()?, splittable: true })
+    }
+
+    /// make `unmap` and `protect` fail, rather than split a block. splitting a block
+    /// takes it away for a moment, which is fatal if it's what we're running from.
+    pub fn forbid_splits(&mut self) {
+        self.splittable = false;
+    }
+
+    /// the physical address of the root table, for a `TTBRn_EL1`.
+    pub fn root(&self) -> Paddr {
+        self.root
+    }
+
+    /// check `vaddr..vaddr + size` is whole pages within the address space.
+    fn check_range(vaddr: Vaddr, size: u64) -> Result<(), MapError> {
+        if vaddr.0 % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(MapError::Misaligned);
+        }
+        match vaddr.0.checked_add(size) {
+            Some(end) if end <= VADDR_MAX + 1 => Ok(()),
+            _ => Err(MapError::OutOfRange),
+        }
+    }
+
+    /// fail if splitting is forbidden and either end of `vaddr..vaddr + size` is in the
+    /// middle of a block.
+    fn check_splits(&self, vaddr: Vaddr, size: u64) -> Result<(), MapError> {
+        if self.splittable || size == 0 {
+            return Ok(());
+        }
+        // the end of the address space is never in the middle of anything
+        let ends = [vaddr.0, vaddr.0 + size];
+        for &addr in ends.iter().filter(|&&addr| addr <= VADDR_MAX) {
+            if let Some((_, level)) = self.leaf(Vaddr(addr)) {
+                if level != PAGE_LEVEL && addr & (entry_size(level) - 1) != 0 {
+                    return Err(MapError::WouldSplit(Vaddr(addr)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// split whatever blocks stick out of either end of `vaddr..vaddr + size`, down to
+    /// pages if need be. the memory stays mapped the same way, so if there's no memory to
+    /// split one with, there's nothing to undo.
+    fn split_edges(&mut self, vaddr: Vaddr, size: u64) -> Result<(), MapError> {
+        if size == 0 {
+            return Ok(());
+        }
+        let ends = [vaddr.0, vaddr.0 + size];
+        for &addr in ends.iter().filter(|&&addr| addr <= VADDR_MAX) {
+            let mut table = self.root;
+            for level in ROOT_LEVEL..PAGE_LEVEL {
+                let entry = unsafe { &mut entries(table)[entry_index(addr, level)] };
+                let descriptor = Descriptor::new(*entry);
+                // nothing at this level or below can stick out past an edge of this entry
+                if !descriptor.is_set(DESCRIPTOR::valid) || addr & (entry_size(level) - 1) == 0 {
+                    break;
+                }
+                table = if is_leaf(descriptor, level) {
+                    unsafe { split_block(entry, addr, level)? }
+                } else {
+                    descriptor_address(descriptor)
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// the leaf descriptor which maps `vaddr`, and its level, if it's mapped at all.
+    fn leaf(&self, vaddr: Vaddr) -> Option<(Descriptor, usize)> {
+        let mut table = self.root;
+        for level in ROOT_LEVEL..=PAGE_LEVEL {
+            let entry = unsafe { entries(table)[entry_index(vaddr.0, level)] };
+            let descriptor = Descriptor::new(entry);
+            if !descriptor.is_set(DESCRIPTOR::valid) {
+                return None;
+            }
+            if is_leaf(descriptor, level) {
+                return Some((descriptor, level));
+            }
+            table = descriptor_address(descriptor);
+        }
+        None
+    }
+
+    /// map `size` bytes at `vaddr` to `paddr` onwards, using the biggest blocks that fit.
+    /// fails, without mapping anything, if any of it is mapped already.
+    pub fn map(&mut self, vaddr: Vaddr, paddr: Paddr, size: u64, attrs: Attributes)
+        -> Result<(), MapError>
+    {
+        Self::check_range(vaddr, size)?;
+        if paddr.0 % PAGE_SIZE != 0 {
+            return Err(MapError::Misaligned);
+        }
+        let mut mapped = 0;
+        let bits = attrs.descriptor_bits();
+        let result = unsafe {
+            map_range(self.root, ROOT_LEVEL, vaddr.0, paddr, size, bits, &mut mapped)
+        };
+        publish();
+        if result.is_err() {
+            // pieces are mapped in order, so this is exactly what we did. the piece which
+            // failed may have left tables behind with nothing in them, anywhere in the range
+            let _ = self.unmap(vaddr, mapped);
+            unsafe { prune_range(self.root, ROOT_LEVEL, vaddr.0, size) };
+        }
+        result
+    }
+
+    /// unmap whatever's mapped in `size` bytes at `vaddr`, splitting any blocks which
+    /// stick out of either end. only fails, without unmapping anything, if a block can't
+    /// be split, or if there's no memory to split one with.
+    pub fn unmap(&mut self, vaddr: Vaddr, size: u64) -> Result<(), MapError> {
+        Self::check_range(vaddr, size)?;
+        self.check_splits(vaddr, size)?;
+        // split first, so that running out of memory partway doesn't leave half of the
+        // range unmapped
+        self.split_edges(vaddr, size)?;
+        unsafe { unmap_range(self.root, ROOT_LEVEL, vaddr.0, size) }
+    }
+
+    /// change the attributes of everything in `size` bytes at `vaddr`, splitting any
+    /// blocks which stick out of either end. fails, without changing anything, unless
+    /// it's all mapped, or if a block can't be split or there's no memory to split one
+    /// with.
+    pub fn protect(&mut self, vaddr: Vaddr, size: u64, attrs: Attributes) -> Result<(), MapError> {
+        Self::check_range(vaddr, size)?;
+        self.check_splits(vaddr, size)?;
+        unsafe {
+            check_mapped(self.root, ROOT_LEVEL, vaddr.0, size)?;
+            self.split_edges(vaddr, size)?;
+            protect_range(self.root, ROOT_LEVEL, vaddr.0, size, attrs.descriptor_bits())
+        }
+    }
+
+    /// where `vaddr` is mapped to, and how, if it's mapped at all.
+    pub fn translate(&self, vaddr: Vaddr) -> Option<(Paddr, Attributes)> {
+        let (descriptor, level) = self.leaf(vaddr)?;
+        let offset = vaddr.0 & (entry_size(level) - 1);
+        let paddr = Paddr(descriptor_address(descriptor).0 + offset);
+        Some((paddr, Attributes::from_descriptor(descriptor)))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe { free_tables(self.root, ROOT_LEVEL) };
+    }
+}
@@ -1,18 +1,23 @@
-use crate::memory::{Paddr, PAGE_SIZE, GIGABYTE, Pointer};
+//! the buddy allocator which hands out physical memory in power-of-two blocks of frames.
+//!
+//! free blocks are kept on a doubly-linked list per size, threaded through the blocks
+//! themselves. alongside the lists there's a bitmap per size with a bit for every block
+//! of that size in the span of physical memory we manage, set when that block is on a
+//! free list. so when a block is freed, finding out whether its buddy is free too and
+//! pulling it off its list to merge them are both O(1).
+//!
+//! with the `framealloc-checks` feature, every `free_frame` is checked against what the
+//! frame table says was allocated there, and freed blocks are filled with `POISON`,
+//! which has to still be there when they're next allocated. anything amiss panics.
+
+use crate::irq;
+use crate::memory::{Paddr, Vaddr, PAGE_SIZE, GIGABYTE, Pointer};
+use crate::memory::physmap::{self, Region, RegionList};
+use crate::memory::{addrspace::{Attributes, MapError}, paging};
+use crate::println;
 use core::convert::From;
 use spin::Mutex;
 use core::ptr;
-use core::ops::{Deref, DerefMut};
-
-#[repr(transparent)]
-#[derive(PartialEq, Eq)]
-/// an owning pointer to a `FreeBlock`.
-///
-/// holding one of these constitutes unique ownership over the `FreeBlock` at `Paddr`, so:
-/// - the pointers can't be copied or cloned
-/// - there must be a valid, initialized `FreeBlock` at `Paddr`
-/// - constructing these is unsafe and is handled by `make_freeblock`
-struct FreeBlockPtr(Paddr);
 
 const fn log2(size: u64) -> usize {
     size.trailing_zeros() as _
@@ -22,9 +27,10 @@ const fn expt2(log_size: usize) -> u64 {
     1 << log_size
 }
 
-const MIN_BLOCK: usize = log2(PAGE_SIZE);
-const MAX_BLOCK: usize = log2(GIGABYTE);
-const N_BLOCK_SIZES: usize = (MAX_BLOCK - MIN_BLOCK + 1) as _;
+/// the log sizes of the smallest and biggest blocks we hand out.
+pub const MIN_BLOCK: usize = log2(PAGE_SIZE);
+pub const MAX_BLOCK: usize = log2(GIGABYTE);
+pub const N_BLOCK_SIZES: usize = (MAX_BLOCK - MIN_BLOCK + 1) as _;
 
 const fn log_size_index(log_size: usize) -> usize {
     log_size - MIN_BLOCK
@@ -35,9 +41,11 @@ const fn valid_block_size(size: u64) -> bool {
     (log_size <= MAX_BLOCK) && (log_size >= MIN_BLOCK)
 }
 
+/// the header at the start of every free block.
 struct FreeBlock {
     log_size: usize,
-    next: Option<FreeBlockPtr>,
+    prev: Option<Paddr>,
+    next: Option<Paddr>,
 }
 
 /// the address of `blk`'s buddy, which may or may not be an actual block.
@@ -53,161 +61,583 @@ fn freeblock_buddy(blk: Paddr, log_size: usize) -> Paddr {
     Paddr::from((aligned ^ 1) << log_size)
 }
 
-impl Deref for FreeBlockPtr {
-    type Target = FreeBlock;
-    fn deref(&self) -> &Self::Target {
-        let ptr = self.0.as_const();
-        unsafe { &*ptr }
-    }
+/// the free block at `blk`.
+///
+/// unsafe because there has to be one there, and nothing else can be looking at it.
+unsafe fn freeblock<'a>(blk: Paddr) -> &'a mut FreeBlock {
+    &mut *blk.as_mut()
 }
 
-impl DerefMut for FreeBlockPtr {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        let ptr = self.0.as_mut();
-        unsafe { &mut *ptr }
-    }
+/// one bit for every block of every size in `span`, which is set when that block is on
+/// a free list. the bits live in frames which `init_frame_allocator` reserves for them.
+struct FreeBitmaps {
+    /// aligned to `MAX_BLOCK` at both ends, so that every buddy of a block in the span
+    /// is in it too.
+    span: Region,
+    words: Paddr,
+    /// where each size's bits start, in words from `words`.
+    offsets: [usize; N_BLOCK_SIZES],
 }
 
-impl FreeBlockPtr {
-    fn split(self) -> (Self, Self) {
-        let newsize = self.log_size - 1;
-        let my_start = self.0;
-        assert!(valid_block_size(expt2(newsize)));
-        let buddy = freeblock_buddy(my_start, newsize);
-        unsafe {
-            // ok because we got took ownership of this memory with `self`
-            (
-                make_freeblock(my_start, newsize, None),
-                make_freeblock(buddy, newsize, None),
-            )
+impl FreeBitmaps {
+    const EMPTY: FreeBitmaps = FreeBitmaps {
+        span: Region { start: Paddr(0), end: Paddr(0) },
+        words: Paddr(0),
+        offsets: [0; N_BLOCK_SIZES],
+    };
+
+    /// the span covering all of `ram`, once it's aligned out to the biggest block.
+    fn span_for(ram: Region) -> Region {
+        Region {
+            start: Paddr(ram.start.0 & !(GIGABYTE - 1)),
+            end: Paddr((ram.end.0 + GIGABYTE - 1) & !(GIGABYTE - 1)),
         }
     }
-    unsafe fn merge(self, buddy: Paddr) -> Self {
-        assert!(buddy == freeblock_buddy(self.0, self.log_size));
-        let start = core::cmp::min(self.0, buddy);
-        let size = self.log_size + 1;
-        make_freeblock(start, size, None)
+
+    fn words_for(span: Region, log_size: usize) -> usize {
+        ((span.size() >> log_size) as usize + 63) / 64
+    }
+
+    /// how many bytes the bitmaps for `span` take.
+    fn bytes_for(span: Region) -> u64 {
+        let words: usize = (MIN_BLOCK..=MAX_BLOCK).map(|s| Self::words_for(span, s)).sum();
+        words as u64 * 8
+    }
+
+    /// clear bitmaps for `span`, in the `bytes_for(span)` bytes at `words`.
+    ///
+    /// unsafe because we take ownership of those bytes.
+    unsafe fn new(span: Region, words: Paddr) -> FreeBitmaps {
+        ptr::write_bytes(words.as_mut::<u8>(), 0, Self::bytes_for(span) as usize);
+        let mut offsets = [0; N_BLOCK_SIZES];
+        let mut offset = 0;
+        for log_size in MIN_BLOCK..=MAX_BLOCK {
+            offsets[log_size_index(log_size)] = offset;
+            offset += Self::words_for(span, log_size);
+        }
+        FreeBitmaps { span, words, offsets }
+    }
+
+    fn covers(&self, region: Region) -> bool {
+        self.span.start <= region.start && region.end <= self.span.end
+    }
+
+    /// the word holding `blk`'s bit, and the bit.
+    fn bit(&self, blk: Paddr, log_size: usize) -> (*mut u64, u64) {
+        assert!(self.span.contains(blk), "{:x} is outside the frame allocator's span", blk);
+        let index = ((blk.0 - self.span.start.0) >> log_size) as usize;
+        let word = self.offsets[log_size_index(log_size)] + index / 64;
+        let words: *mut u64 = self.words.as_mut();
+        (unsafe { words.add(word) }, 1 << (index % 64))
+    }
+
+    fn is_free(&self, blk: Paddr, log_size: usize) -> bool {
+        let (word, bit) = self.bit(blk, log_size);
+        unsafe { *word & bit != 0 }
+    }
+
+    fn set_free(&mut self, blk: Paddr, log_size: usize, free: bool) {
+        let (word, bit) = self.bit(blk, log_size);
+        unsafe {
+            if free {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
     }
 }
 
-/// takes unique ownership of the `expt2(log_size)` bytes of memory starting at `start`.
-unsafe fn make_freeblock(
-    start: Paddr,
-    log_size: usize,
-    next: Option<FreeBlockPtr>,
-) -> FreeBlockPtr {
-    let ptr = u64::from(start.0) as *mut FreeBlock;
-    ptr::write(ptr, FreeBlock { log_size, next });
-    FreeBlockPtr(start)
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// what a frame of physical memory is being used for.
+pub enum FrameState {
+    /// not ram at all, just a hole in the span the allocator keeps track of.
+    Absent,
+    Free,
+    /// ram which the allocator never hands out.
+    Reserved,
+    /// allocated for the kernel's own use.
+    Kernel,
+    PageTable,
+    User,
+    Slab,
 }
 
-struct FrameAllocator {
-    freelist: [Option<FreeBlockPtr>; N_BLOCK_SIZES],
+impl FrameState {
+    pub fn is_allocated(self) -> bool {
+        !matches!(self, FrameState::Absent | FrameState::Free | FrameState::Reserved)
+    }
 }
 
-unsafe fn duplicate_freeblock(&FreeBlockPtr(ptr): &FreeBlockPtr) -> FreeBlockPtr {
-    FreeBlockPtr(ptr)
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+/// what we know about one frame of physical memory.
+///
+/// an allocated block's state and owner are recorded in every frame of it, but its
+/// reference count and size only in the first.
+pub struct FrameInfo {
+    pub state: FrameState,
+    log_size: u8,
+    pub refcount: u32,
+    /// an id for whoever the frame belongs to, which only means something to them, like
+    /// an address space's asid. `NO_OWNER` for the kernel's own frames.
+    pub owner: u32,
+    /// whether the frame was filled with `POISON` when it was freed.
+    #[cfg(feature = "framealloc-checks")]
+    poisoned: bool,
 }
 
-fn find_buddy(
-    start: Paddr,
-    log_size: usize,
-    freelist: Option<FreeBlockPtr>,
-) -> (Option<FreeBlockPtr>, Option<FreeBlockPtr>) {
-    if let Some(mut first) = freelist {
-        let target = freeblock_buddy(start, log_size);
-        if first.0 == target {
-            let tail = first.next.take();
-            (Some(first), tail)
+pub const NO_OWNER: u32 = 0;
+
+impl FrameInfo {
+    const ABSENT: FrameInfo = FrameInfo {
+        state: FrameState::Absent,
+        log_size: 0,
+        refcount: 0,
+        owner: NO_OWNER,
+        #[cfg(feature = "framealloc-checks")]
+        poisoned: false,
+    };
+
+    /// the size of the block this frame starts, if it's the first frame of an allocated
+    /// block.
+    pub fn block_size(&self) -> Option<u64> {
+        if self.state.is_allocated() && self.refcount > 0 {
+            Some(expt2(self.log_size as usize))
         } else {
-            // we do some shenanigans here to get a shared local mutable ref to various
-            // parts of the linked list. this is nasty, but i can't think of a better way,
-            // and it's safe since none of the references escape, and no two `&mut`s ever
-            // coexist to the same place.
-            let mut already_traversed = unsafe { duplicate_freeblock(&first) };
-            while let Some(mut next) = already_traversed.next.take() {
-                if next.0 == target {
-                    already_traversed.next = next.next.take();
-                    return (Some(next), Some(first))
-                } else {
-                    already_traversed.next = Some(unsafe { duplicate_freeblock(&next) });
-                    already_traversed = next;
-                }
+            None
+        }
+    }
+}
+
+/// a `FrameInfo` for every frame in the same span as `FreeBitmaps`, in frames which
+/// `init_frame_allocator` reserves for it.
+struct FrameTable {
+    span: Region,
+    infos: Paddr,
+}
+
+impl FrameTable {
+    const EMPTY: FrameTable = FrameTable {
+        span: Region { start: Paddr(0), end: Paddr(0) },
+        infos: Paddr(0),
+    };
+
+    fn bytes_for(span: Region) -> u64 {
+        (span.size() >> MIN_BLOCK) * core::mem::size_of::<FrameInfo>() as u64
+    }
+
+    /// a table for `span`, in the `bytes_for(span)` bytes at `infos`, with every frame
+    /// absent.
+    ///
+    /// unsafe because we take ownership of those bytes.
+    unsafe fn new(span: Region, infos: Paddr) -> FrameTable {
+        let mut table = FrameTable { span, infos };
+        table.set_range(span, FrameInfo::ABSENT);
+        table
+    }
+
+    fn info_ptr(&self, frame: Paddr) -> Option<*mut FrameInfo> {
+        if !self.span.contains(frame) {
+            return None;
+        }
+        let index = ((frame.0 - self.span.start.0) >> MIN_BLOCK) as usize;
+        let infos: *mut FrameInfo = self.infos.as_mut();
+        Some(unsafe { infos.add(index) })
+    }
+
+    fn get(&self, frame: Paddr) -> Option<FrameInfo> {
+        self.info_ptr(frame).map(|info| unsafe { *info })
+    }
+
+    fn get_mut(&mut self, frame: Paddr) -> Option<&mut FrameInfo> {
+        // the table owns `infos`, so borrowing it mutably is enough
+        self.info_ptr(frame).map(|info| unsafe { &mut *info })
+    }
+
+    #[cfg(feature = "framealloc-checks")]
+    fn set_poisoned(&mut self, region: Region) {
+        let mut frame = region.start;
+        while frame < region.end {
+            if let Some(slot) = self.get_mut(frame) {
+                slot.poisoned = true;
             }
-            (None, Some(first))
+            frame.0 += PAGE_SIZE;
         }
-    } else {
-        (None, None)
     }
+
+    /// set every frame of `region` to `info`.
+    fn set_range(&mut self, region: Region, info: FrameInfo) {
+        let mut frame = region.start;
+        while frame < region.end {
+            if let Some(slot) = self.get_mut(frame) {
+                *slot = info;
+            }
+            frame.0 += PAGE_SIZE;
+        }
+    }
+}
+
+struct FrameAllocator {
+    freelist: [Option<Paddr>; N_BLOCK_SIZES],
+    /// how many blocks are on each free list.
+    free_blocks: [usize; N_BLOCK_SIZES],
+    /// bytes handed out and not yet freed, and the most that's ever been.
+    allocated: u64,
+    high_water: u64,
+    bitmaps: FreeBitmaps,
+    frames: FrameTable,
+    /// set by `init_frame_allocator`. before then, reservations are only recorded, since
+    /// there are no free lists to carve them out of.
+    initialized: bool,
 }
 
+// the free blocks are only ever touched through the lock
+unsafe impl Send for FrameAllocator {}
+
 impl core::ops::Index<usize> for FrameAllocator {
-    type Output = Option<FreeBlockPtr>;
-    fn index(&self, i: usize) -> &Option<FreeBlockPtr> {
+    type Output = Option<Paddr>;
+    fn index(&self, i: usize) -> &Option<Paddr> {
         &self.freelist[log_size_index(i)]
     }
 }
 
 impl core::ops::IndexMut<usize> for FrameAllocator {
-    fn index_mut(&mut self, i: usize) -> &mut Option<FreeBlockPtr> {
+    fn index_mut(&mut self, i: usize) -> &mut Option<Paddr> {
         &mut self.freelist[log_size_index(i)]
     }
 }
 
 impl FrameAllocator {
-    fn alloc(&mut self, size: u64) -> Option<FreeBlockPtr> {
+    /// put the block at `blk` on the front of its free list.
+    ///
+    /// unsafe because this takes ownership of the block.
+    unsafe fn push(&mut self, blk: Paddr, log_size: usize) {
+        let next = self[log_size];
+        if let Some(next) = next {
+            freeblock(next).prev = Some(blk);
+        }
+        ptr::write(blk.as_mut(), FreeBlock { log_size, prev: None, next });
+        self[log_size] = Some(blk);
+        self.bitmaps.set_free(blk, log_size, true);
+        self.free_blocks[log_size_index(log_size)] += 1;
+    }
+    /// take the block at `blk`, which has to be free, off its list.
+    fn unlink(&mut self, blk: Paddr, log_size: usize) {
+        debug_assert!(self.bitmaps.is_free(blk, log_size));
+        let &mut FreeBlock { log_size: actual, prev, next } = unsafe { freeblock(blk) };
+        debug_assert_eq!(actual, log_size);
+        match prev {
+            Some(prev) => unsafe { freeblock(prev).next = next },
+            None => self[log_size] = next,
+        }
+        if let Some(next) = next {
+            unsafe { freeblock(next).prev = prev };
+        }
+        self.bitmaps.set_free(blk, log_size, false);
+        self.free_blocks[log_size_index(log_size)] -= 1;
+    }
+    fn alloc(&mut self, size: u64, state: FrameState, owner: u32) -> Option<Paddr> {
         assert!(valid_block_size(size));
+        assert!(state.is_allocated(), "allocating frames to be {:?}", state);
         let log_size = log2(size) as usize;
-        if let Some(mut blk) = self[log_size].take() {
-            let tail = blk.next.take();
-            self[log_size] = tail;
-            Some(blk)
-        } else if log_size == MAX_BLOCK {
-            None
-        } else if let Some(larger) = self.alloc(size << 1) {
-            let (ret, buddy) = larger.split();
-            self[log_size] = Some(buddy);
-            Some(ret)
-        } else {
-            None
+        let (mut blk_size, blk) = (log_size..=MAX_BLOCK)
+            .find_map(|s| self[s].map(|blk| (s, blk)))?;
+        self.unlink(blk, blk_size);
+        // give back the top half until it's the size we want
+        while blk_size > log_size {
+            blk_size -= 1;
+            // ok because we took ownership of the whole block
+            unsafe { self.push(Paddr(blk.0 + expt2(blk_size)), blk_size) };
         }
+        #[cfg(feature = "framealloc-checks")]
+        self.check_poison(blk, size);
+        let info = FrameInfo {
+            state,
+            log_size: log_size as u8,
+            refcount: 0,
+            owner,
+            ..FrameInfo::ABSENT
+        };
+        self.frames.set_range(Region::new(blk, size), info);
+        self.frames.get_mut(blk).unwrap().refcount = 1;
+        self.allocated += size;
+        self.high_water = self.high_water.max(self.allocated);
+        Some(blk)
     }
-    fn free(&mut self, start: Paddr, size: u64) {
-        assert!(valid_block_size(size));
-        let log_size = log2(size);
-        let tail = self[log_size].take();
-        let (buddy, tail) = find_buddy(start, log_size, tail);
-        if let Some(buddy) = buddy {
-            self[log_size] = tail;
-            self.free(start.min(buddy.0), expt2(log_size + 1));
-        } else {
-            self[log_size] = Some(unsafe {
-                make_freeblock(start, log_size, tail)
+    /// drop a reference to the allocated block at `start`, and free it if that was the
+    /// last one.
+    ///
+    /// `size` is only checked, with `framealloc-checks` on; what's freed is the block the
+    /// frame table says starts at `start`. any of it in `reserved` stays out of the free
+    /// lists.
+    ///
+    /// unsafe because this takes ownership of the block if it's freed.
+    #[cfg_attr(not(feature = "framealloc-checks"), allow(unused_variables))]
+    unsafe fn release(
+        &mut self,
+        start: Paddr,
+        size: u64,
+        reserved: &RegionList<MAX_RESERVED_REGIONS>,
+    ) {
+        #[cfg(feature = "framealloc-checks")]
+        self.check_free(start, size);
+        let info = self.frames.get_mut(start)
+            .unwrap_or_else(|| panic!("freeing {:x}, which isn't ram", start));
+        assert!(info.refcount > 0, "freeing {:x}, which is {:?}", start, info.state);
+        info.refcount -= 1;
+        if info.refcount == 0 {
+            // the frame table knows how big the block really is, whatever the caller said
+            let size = expt2(info.log_size as usize);
+            self.allocated -= size;
+            // some of it might have been reserved since it was allocated, in which case
+            // that part stays reserved, and only the rest is freed
+            let block = Region::new(start, size);
+            self.frames.set_range(block, FrameInfo {
+                state: FrameState::Reserved,
+                ..FrameInfo::ABSENT
             });
+            reserved.for_each_gap(block, |piece| {
+                #[cfg(feature = "framealloc-checks")]
+                poison(piece.start, piece.size());
+                self.free_range(piece);
+                #[cfg(feature = "framealloc-checks")]
+                self.frames.set_poisoned(piece);
+            });
+        }
+    }
+    /// unsafe because this takes ownership of the block.
+    unsafe fn free(&mut self, mut start: Paddr, size: u64) {
+        assert!(valid_block_size(size));
+        self.frames.set_range(Region::new(start, size), FrameInfo {
+            state: FrameState::Free,
+            ..FrameInfo::ABSENT
+        });
+        let mut log_size = log2(size);
+        while log_size < MAX_BLOCK {
+            let buddy = freeblock_buddy(start, log_size);
+            if !self.bitmaps.is_free(buddy, log_size) {
+                break;
+            }
+            self.unlink(buddy, log_size);
+            // the upper half's header is just garbage in the middle of the merged block
+            #[cfg(feature = "framealloc-checks")]
+            poison(start.max(buddy), FREEBLOCK_HEADER);
+            start = start.min(buddy);
+            log_size += 1;
+        }
+        self.push(start, log_size);
+    }
+    /// add all of `region` to the free lists without trying to merge with its
+    /// neighbors. `region` must be page-aligned.
+    unsafe fn add_range(&mut self, Region { start, end }: Region) {
+        self.frames.set_range(Region { start, end }, FrameInfo {
+            state: FrameState::Free,
+            ..FrameInfo::ABSENT
+        });
+        for (start, log_size) in (FramesIterator { start, end }) {
+            self.push(start, log_size);
+        }
+    }
+    /// add all of `region` to the free lists, merging with any free buddies. `region`
+    /// must be page-aligned.
+    unsafe fn free_range(&mut self, Region { start, end }: Region) {
+        for (start, log_size) in (FramesIterator { start, end }) {
+            self.free(start, expt2(log_size));
+        }
+    }
+    /// pull every free block which overlaps `hole` out of the free lists, and give back
+    /// the parts of them which lie outside `hole`. `hole` must be page-aligned.
+    fn remove_range(&mut self, hole: Region) {
+        // the leftover pieces of a block are always smaller than it, so they land on
+        // lists we haven't visited yet, and since they don't overlap `hole`, we'll leave
+        // them be when we get there.
+        for log_size in (MIN_BLOCK..=MAX_BLOCK).rev() {
+            let mut cur = self[log_size];
+            while let Some(blk) = cur {
+                cur = unsafe { freeblock(blk).next };
+                let block = Region::new(blk, expt2(log_size));
+                if block.overlaps(&hole) {
+                    self.unlink(blk, log_size);
+                    self.frames.set_range(block.intersection(&hole), FrameInfo {
+                        state: FrameState::Reserved,
+                        ..FrameInfo::ABSENT
+                    });
+                    let below = Region { start: block.start, end: hole.start };
+                    let above = Region { start: hole.end, end: block.end };
+                    for piece in [below, above].iter().filter(|p| !p.is_empty()) {
+                        // ok because we owned the whole block
+                        unsafe { self.add_range(*piece) };
+                    }
+                }
+            }
+        }
+    }
+    /// call `f` on each free block, and its log size.
+    fn for_each_free_block<F: FnMut(Paddr, usize)>(&self, mut f: F) {
+        for log_size in MIN_BLOCK..=MAX_BLOCK {
+            let mut cur = self[log_size];
+            while let Some(blk) = cur {
+                f(blk, log_size);
+                cur = unsafe { freeblock(blk).next };
+            }
         }
     }
-    unsafe fn add_block(&mut self, start: Paddr, log_size: usize) {
-        let tail = self[log_size].take();
-        self[log_size] = Some(make_freeblock(start, log_size, tail));
+    /// how many bytes of `region` are in the free lists.
+    fn free_bytes_in(&self, region: Region) -> u64 {
+        let mut total = 0;
+        self.for_each_free_block(|blk, log_size| {
+            total += Region::new(blk, expt2(log_size)).intersection(&region).size();
+        });
+        total
+    }
+    /// how many bytes in total are in the free lists, and in how many blocks.
+    fn free_totals(&self) -> (u64, usize) {
+        let (mut bytes, mut blocks) = (0, 0);
+        for log_size in MIN_BLOCK..=MAX_BLOCK {
+            let count = self.free_blocks[log_size_index(log_size)];
+            bytes += expt2(log_size) * count as u64;
+            blocks += count;
+        }
+        (bytes, blocks)
     }
 }
 
-const NONE_FREEBLOCK: Option<FreeBlockPtr> = None;
+#[cfg(feature = "framealloc-checks")]
+impl FrameAllocator {
+    /// panic unless `start` is the start of a live allocation of `size` bytes.
+    fn check_free(&self, start: Paddr, size: u64) {
+        let fail = |problem: core::fmt::Arguments| -> ! {
+            panic!("bad free_frame({:x}, {:#x}): {}", start, size, problem)
+        };
+        if !size.is_power_of_two() || !valid_block_size(size) {
+            fail(format_args!("that isn't a block size"));
+        }
+        if start.0 % size != 0 {
+            fail(format_args!("the block isn't aligned to its size"));
+        }
+        let info = match self.frames.get(start) {
+            Some(info) => info,
+            None => fail(format_args!("it's outside the ram the allocator keeps track of")),
+        };
+        let allocated_size = expt2(info.log_size as usize);
+        match info.state {
+            FrameState::Free => fail(format_args!("it's free already")),
+            FrameState::Absent | FrameState::Reserved => {
+                fail(format_args!("it's {:?}, so it was never allocated", info.state))
+            }
+            _ if info.refcount == 0 => {
+                fail(format_args!("it's in the middle of a block, not at the start of one"))
+            }
+            _ if allocated_size != size => {
+                fail(format_args!("it was allocated as {:#x} bytes", allocated_size))
+            }
+            _ => {}
+        }
+    }
 
+    /// panic unless every poisoned frame of the block at `blk` is still all `POISON`,
+    /// apart from the free block header at the very start.
+    fn check_poison(&self, blk: Paddr, size: u64) {
+        let mut frame = blk;
+        while frame.0 < blk.0 + size {
+            if self.frames.get(frame).map_or(false, |info| info.poisoned) {
+                let skip = if frame == blk { FREEBLOCK_HEADER } else { 0 };
+                let words: *const u64 = frame.as_const();
+                for offset in (skip..PAGE_SIZE).step_by(8) {
+                    let found = unsafe { words.add(offset as usize / 8).read_volatile() };
+                    assert!(
+                        found == POISON,
+                        "{:x}, {:#x} bytes into the freed block {:x}, was overwritten with \
+                         {:#018x} after it was freed",
+                        Paddr(frame.0 + offset), frame.0 + offset - blk.0, blk, found,
+                    );
+                }
+            }
+            frame.0 += PAGE_SIZE;
+        }
+    }
+}
+
+/// what freed blocks are filled with when checking.
+#[cfg(feature = "framealloc-checks")]
+const POISON: u64 = 0x6b6b_6b6b_6b6b_6b6b;
+
+/// the bytes at the start of a free block which its `FreeBlock` can take up, rounded up
+/// to whole words.
+#[cfg(feature = "framealloc-checks")]
+const FREEBLOCK_HEADER: u64 = ((core::mem::size_of::<FreeBlock>() + 7) & !7) as u64;
+
+/// fill `size` bytes at `start` with `POISON`.
+#[cfg(feature = "framealloc-checks")]
+fn poison(start: Paddr, size: u64) {
+    let words: *mut u64 = start.as_mut();
+    for i in 0..(size / 8) as usize {
+        unsafe { words.add(i).write_volatile(POISON) };
+    }
+}
+
+const NONE_FREEBLOCK: Option<Paddr> = None;
+
+/// only ever locked through `irq::without_interrupts`.
 static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator {
     freelist: [NONE_FREEBLOCK; N_BLOCK_SIZES],
+    free_blocks: [0; N_BLOCK_SIZES],
+    allocated: 0,
+    high_water: 0,
+    bitmaps: FreeBitmaps::EMPTY,
+    frames: FrameTable::EMPTY,
+    initialized: false,
 });
 
+/// how many discontiguous reserved regions we can keep track of.
+const MAX_RESERVED_REGIONS: usize = 32;
+
+/// physical memory which the allocator must never hand out, even though it's ram: the
+/// kernel image, the device tree, firmware carve-outs and the like.
+///
+/// lock ordering: `paging::kernel_space()`, then `RESERVED`, then `physmap`'s lock, then
+/// `FRAME_ALLOCATOR`. only ever locked through `irq::without_interrupts`.
+static RESERVED: Mutex<RegionList<MAX_RESERVED_REGIONS>> = Mutex::new(RegionList::new());
+
+/// a block of `size` bytes, for the kernel.
 pub fn alloc_frame(size: u64) -> Option<Paddr> {
-    if let Some(block) = FRAME_ALLOCATOR.lock().alloc(size) {
-        Some(block.0)
-    } else {
-        None
-    }
+    alloc_frame_for(size, FrameState::Kernel, NO_OWNER)
+}
+
+/// a block of `size` bytes, which the frame table will say is being used as `state` by
+/// `owner`. it starts with one reference.
+pub fn alloc_frame_for(size: u64, state: FrameState, owner: u32) -> Option<Paddr> {
+    irq::without_interrupts(|| FRAME_ALLOCATOR.lock().alloc(size, state, owner))
 }
 
+/// take another reference to the block starting at `frame`, so that it takes another
+/// `free_frame` to free it. returns how many references there are now.
+pub fn ref_frame(frame: Paddr) -> u32 {
+    irq::without_interrupts(|| {
+        let mut alloc = FRAME_ALLOCATOR.lock();
+        let info = alloc.frames.get_mut(frame)
+            .filter(|info| info.refcount > 0)
+            .unwrap_or_else(|| panic!("taking a reference to {:x}, which isn't allocated", frame));
+        info.refcount += 1;
+        info.refcount
+    })
+}
+
+/// drop a reference to the block of `size` bytes at `frame`, and free it if that was the
+/// last one.
 pub unsafe fn free_frame(frame: Paddr, size: u64) {
-    FRAME_ALLOCATOR.lock().free(frame, size);
+    irq::without_interrupts(|| {
+        let reserved = RESERVED.lock();
+        FRAME_ALLOCATOR.lock().release(frame, size, &reserved);
+    });
+}
+
+/// what the frame table says about `frame`, or `None` if it's outside the span of
+/// physical memory the allocator keeps track of.
+pub fn frame_info(frame: Paddr) -> Option<FrameInfo> {
+    irq::without_interrupts(|| FRAME_ALLOCATOR.lock().frames.get(frame))
 }
 
 struct FramesIterator {
@@ -235,12 +665,295 @@ impl Iterator for FramesIterator {
     }
 }
 
-/// takes unique ownership of all the memory in the range `start..end`. usual invariants
+#[derive(Copy, Clone, Debug)]
+/// why `add_memory` or `remove_memory` failed.
+pub enum HotplugError {
+    /// some of the region is outside the span of physical addresses the allocator keeps
+    /// track of.
+    OutsideSpan(Region),
+    /// some of the region is allocated or reserved.
+    InUse(Region),
+    /// the linear map couldn't be changed to match.
+    Map(MapError),
+}
+
+/// never hand out any of `region`, which is rounded out to whole pages.
+///
+/// reservations made before `init_frame_allocator` are honored when it builds the free
+/// lists. reservations made after are carved out of the free lists, and any part of
+/// `region` which is currently allocated stays with its owner until it's freed, and is
+/// left reserved then rather than going back on the free lists.
+pub fn reserve(region: Region) {
+    let region = region.page_align_outward();
+    if region.is_empty() {
+        return;
+    }
+    irq::without_interrupts(|| {
+        RESERVED.lock().insert(region);
+        let mut alloc = FRAME_ALLOCATOR.lock();
+        if alloc.initialized {
+            alloc.remove_range(region);
+        }
+    });
+}
+
+/// reserve the first stretch of free ram that `bytes` fit in, for the allocator's own
+/// bookkeeping.
+fn carve_out(reserved: &mut RegionList<MAX_RESERVED_REGIONS>, bytes: u64) -> Option<Paddr> {
+    let mut found = None;
+    physmap::for_each_ram_region(|ram| {
+        reserved.for_each_gap(ram.page_align_inward(), |piece| {
+            if found.is_none() && piece.size() >= bytes {
+                found = Some(piece.start);
+            }
+        });
+    });
+    let start = found?;
+    reserved.insert(Region::new(start, bytes).page_align_outward());
+    Some(start)
+}
+
+/// takes unique ownership of every page of ram in the physical memory map which isn't
+/// reserved. usual invariants apply; no other references to that memory may exist.
+pub unsafe fn init_frame_allocator() {
+    let mut reserved = RESERVED.lock();
+
+    let mut ram_span: Option<Region> = None;
+    physmap::for_each_ram_region(|ram| {
+        ram_span = Some(match ram_span {
+            Some(span) => Region { start: span.start, end: ram.end },
+            None => ram,
+        });
+    });
+    let span = FreeBitmaps::span_for(ram_span.expect("the physical memory map is empty"));
+
+    let bitmaps = carve_out(&mut reserved, FreeBitmaps::bytes_for(span))
+        .expect("no room in ram for the frame allocator's bitmaps");
+    let frames = carve_out(&mut reserved, FrameTable::bytes_for(span))
+        .expect("no room in ram for the frame table");
+    {
+        let mut alloc = FRAME_ALLOCATOR.lock();
+        alloc.bitmaps = FreeBitmaps::new(span, bitmaps);
+        alloc.frames = FrameTable::new(span, frames);
+    }
+    // whatever ram doesn't make it onto the free lists below is reserved
+    physmap::for_each_ram_region(|ram| {
+        FRAME_ALLOCATOR.lock().frames.set_range(ram, FrameInfo {
+            state: FrameState::Reserved,
+            ..FrameInfo::ABSENT
+        });
+    });
+
+    physmap::for_each_ram_region(|ram| {
+        let mut alloc = FRAME_ALLOCATOR.try_lock()
+            .expect("FRAME_ALLOCATOR already locked when initializing.");
+        reserved.for_each_gap(ram.page_align_inward(), |piece| alloc.add_range(piece));
+    });
+    FRAME_ALLOCATOR.lock().initialized = true;
+}
+
+/// hand the ram in `region`, shrunk to whole pages, to the allocator after init, minus
+/// any parts of it which are reserved or which were ram already. usual invariants
 /// apply; no other references to that memory may exist.
-pub unsafe fn init_frame_allocator(start: Paddr, end: Paddr) {
-    let mut alloc = FRAME_ALLOCATOR.try_lock()
-        .expect("FRAME_ALLOCATOR already locked when initializing.");
-    for (start, size) in (FramesIterator { start, end: Paddr(end.0 + 1) }) {
-        alloc.add_block(start, size);
+///
+/// the allocator only keeps track of the span of physical addresses which ram was in at
+/// init, rounded out to 1 GiB at each end. fails without changing anything if `region`
+/// goes outside that, or if there's no memory to add it to the linear map with.
+pub unsafe fn add_memory(region: Region) -> Result<(), HotplugError> {
+    let region = region.page_align_inward();
+    irq::without_interrupts(|| {
+        if !FRAME_ALLOCATOR.lock().bitmaps.covers(region) {
+            return Err(HotplugError::OutsideSpan(region));
+        }
+        // holding the kernel's tables keeps any other hotplug out until we're done
+        let mut space = paging::kernel_space().lock();
+        // the new ram has to be in the linear map before it can be handed out. any page
+        // it shares with ram we knew about is mapped already
+        let mut new = RegionList::<{ physmap::MAX_RAM_REGIONS + 1 }>::new();
+        physmap::for_each_gap(region, |gap| new.insert(gap.page_align_inward()));
+        for (i, gap) in new.iter().enumerate() {
+            let mapped = space.map(Vaddr(gap.start.0), gap.start, gap.size(), Attributes::KERNEL);
+            if let Err(err) = mapped {
+                // these were mapped with blocks from their own edges, so they unmap
+                // without splitting anything
+                for gap in new.iter().take(i) {
+                    let _ = space.unmap(Vaddr(gap.start.0), gap.size());
+                }
+                return Err(HotplugError::Map(err));
+            }
+        }
+        // ram we knew about already is either free or somebody's, and either way it
+        // mustn't be freed again
+        let reserved = RESERVED.lock();
+        physmap::add_ram(region, |new| {
+            let mut alloc = FRAME_ALLOCATOR.lock();
+            reserved.for_each_gap(new, |piece| alloc.free_range(piece));
+        });
+        Ok(())
+    })
+}
+
+/// take the ram in `region`, rounded out to whole pages, away from the allocator and
+/// out of the linear map for good. fails without changing anything if any page of
+/// `region` isn't free, or if taking it out of the linear map would split a block.
+pub fn remove_memory(region: Region) -> Result<(), HotplugError> {
+    let region = region.page_align_outward();
+    irq::without_interrupts(|| {
+        // holding the kernel's tables keeps any other hotplug out until we're done
+        let mut space = paging::kernel_space().lock();
+        {
+            let reserved = RESERVED.lock();
+            let mut alloc = FRAME_ALLOCATOR.lock();
+            if reserved.overlaps(&region) || alloc.free_bytes_in(region) != region.size() {
+                return Err(HotplugError::InUse(region));
+            }
+            alloc.remove_range(region);
+        }
+        // unmapping can free tables, which needs the locks we just dropped
+        if let Err(err) = space.unmap(Vaddr(region.start.0), region.size()) {
+            // ok because it was all free a moment ago, and nobody else could have it
+            unsafe { FRAME_ALLOCATOR.lock().free_range(region) };
+            return Err(HotplugError::Map(err));
+        }
+        FRAME_ALLOCATOR.lock().frames.set_range(region, FrameInfo::ABSENT);
+        physmap::remove_ram(region);
+        Ok(())
+    })
+}
+
+/// print the ram regions, the reserved regions and how much is free.
+pub fn print_layout() {
+    println!("physical memory layout:");
+    physmap::for_each_ram_region(|region| {
+        println!("    ram      {:x} {:>8} KiB", region, region.size() >> 10);
+    });
+    irq::without_interrupts(|| {
+        for region in RESERVED.lock().iter() {
+            println!("    reserved {:x} {:>8} KiB", region, region.size() >> 10);
+        }
+    });
+    let (bytes, blocks) = irq::without_interrupts(|| FRAME_ALLOCATOR.lock().free_totals());
+    println!("    {} KiB free in {} blocks", bytes >> 10, blocks);
+}
+
+#[derive(Copy, Clone, Debug)]
+/// a snapshot of how physical memory is being used.
+pub struct FrameStats {
+    /// all the ram in the physical memory map.
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    /// ram which is never handed out, including the allocator's own bookkeeping.
+    pub reserved_bytes: u64,
+    pub allocated_bytes: u64,
+    /// the most that's ever been allocated at once.
+    pub high_water_bytes: u64,
+    /// how many free blocks there are of each size, indexed by log size minus
+    /// `MIN_BLOCK`.
+    pub free_blocks: [usize; N_BLOCK_SIZES],
+}
+
+impl FrameStats {
+    /// how many free blocks of `size` bytes there are.
+    pub fn free_blocks_of(&self, size: u64) -> usize {
+        assert!(valid_block_size(size));
+        self.free_blocks[log_size_index(log2(size))]
+    }
+
+    /// the biggest block which can be allocated right now, if any.
+    pub fn largest_free_block(&self) -> Option<u64> {
+        (MIN_BLOCK..=MAX_BLOCK).rev()
+            .find(|&log_size| self.free_blocks[log_size_index(log_size)] > 0)
+            .map(expt2)
+    }
+
+    /// the percentage of free memory which is in blocks too small for an allocation of
+    /// `size` bytes: 0 if all of it could be used, 100 if none of it could.
+    pub fn fragmentation(&self, size: u64) -> u64 {
+        assert!(valid_block_size(size));
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        let usable: u64 = (log2(size)..=MAX_BLOCK)
+            .map(|log_size| expt2(log_size) * self.free_blocks[log_size_index(log_size)] as u64)
+            .sum();
+        (self.free_bytes - usable) * 100 / self.free_bytes
+    }
+
+    /// `fragmentation` for the biggest block the free memory could make up, if it were
+    /// all in one piece. 0 means it's as contiguous as it can be.
+    pub fn fragmentation_index(&self) -> u64 {
+        if self.free_bytes < PAGE_SIZE {
+            return 0;
+        }
+        let best = (63 - self.free_bytes.leading_zeros() as usize).min(MAX_BLOCK);
+        self.fragmentation(expt2(best))
+    }
+}
+
+pub fn stats() -> FrameStats {
+    irq::without_interrupts(|| {
+        let reserved = RESERVED.lock();
+        let (mut total_bytes, mut reserved_bytes) = (0, 0);
+        physmap::for_each_ram_region(|ram| {
+            total_bytes += ram.size();
+            reserved_bytes += reserved.iter().map(|r| r.intersection(&ram).size()).sum::<u64>();
+        });
+        let alloc = FRAME_ALLOCATOR.lock();
+        FrameStats {
+            total_bytes,
+            free_bytes: alloc.free_totals().0,
+            reserved_bytes,
+            allocated_bytes: alloc.allocated,
+            high_water_bytes: alloc.high_water,
+            free_blocks: alloc.free_blocks,
+        }
+    })
+}
+
+/// `bytes` in whichever of KiB, MiB and GiB it's a whole number of, or KiB if none.
+/// right-aligned to the width, if there is one.
+struct Size(u64);
+
+impl core::fmt::Display for Size {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        use core::fmt::Write;
+        let (value, unit) = match self.0 {
+            bytes if bytes != 0 && bytes % GIGABYTE == 0 => (bytes >> 30, "GiB"),
+            bytes if bytes != 0 && bytes % (1 << 20) == 0 => (bytes >> 20, "MiB"),
+            bytes => (bytes >> 10, "KiB"),
+        };
+        // this gets printed when we're out of memory, so it can't go through a `String`
+        let mut digits = 1;
+        while value >= 10u64.pow(digits as u32) {
+            digits += 1;
+        }
+        let padding = f.width().unwrap_or(0).saturating_sub(digits + 1 + unit.len());
+        for _ in 0..padding {
+            f.write_char(' ')?;
+        }
+        write!(f, "{} {}", value, unit)
+    }
+}
+
+/// print `stats()`, with a line for each block size.
+pub fn print_stats() {
+    let stats = stats();
+    println!("frame allocator:");
+    println!("    total      {:>12}", Size(stats.total_bytes));
+    println!("    free       {:>12}", Size(stats.free_bytes));
+    println!("    reserved   {:>12}", Size(stats.reserved_bytes));
+    println!("    allocated  {:>12}, at most {}",
+             Size(stats.allocated_bytes), Size(stats.high_water_bytes));
+    if let Some(size) = stats.largest_free_block() {
+        println!("    largest free block {}", Size(size));
+    } else {
+        println!("    no free blocks");
+    }
+    println!("    fragmentation index {}%", stats.fragmentation_index());
+    println!("    {:>8} {:>8} {:>12}", "size", "free", "unusable");
+    for log_size in MIN_BLOCK..=MAX_BLOCK {
+        let size = expt2(log_size);
+        println!("    {:>8} {:>8} {:>11}%",
+                 Size(size), stats.free_blocks_of(size), stats.fragmentation(size));
     }
 }
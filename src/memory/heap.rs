@@ -0,0 +1,243 @@
+//! the kernel heap, which is what `alloc`'s `Box`, `Vec` and friends allocate from.
+//!
+//! small allocations come out of a first-fit free list, kept sorted by address so that
+//! neighbouring free blocks can be merged back together. when nothing on the list is
+//! big enough, the heap grows by taking another chunk of frames from `framealloc`. big
+//! allocations skip the list and get frames of their own, which go straight back when
+//! they're freed. chunks the list has taken are never given back.
+//!
+//! there's nothing to set up, but nothing can be allocated until the frame allocator is.
+
+use crate::irq;
+use crate::memory::{framealloc, kernel_paddr, Pointer, GIGABYTE, PAGE_SIZE};
+use crate::println;
+use core::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+use spin::Mutex;
+
+/// everything on the free list is a multiple of this, in both size and alignment, so
+/// there's always room for a `FreeBlock` in whatever's left over from a split.
+const BLOCK_ALIGN: usize = mem::size_of::<FreeBlock>();
+
+/// the least the heap grows by at once.
+const MIN_GROWTH: u64 = 16 * PAGE_SIZE;
+
+/// allocations bigger than this get frames of their own.
+const MAX_SMALL_ALLOC: usize = 4 * PAGE_SIZE as usize;
+
+#[repr(C, align(16))]
+/// the header at the start of each free block.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    /// the lowest addressed free block.
+    head: *mut FreeBlock,
+    /// how much the free list has taken from `framealloc`.
+    grown: u64,
+    /// how much is handed out right now, from the free list or as frames.
+    in_use: u64,
+}
+
+// the free blocks are only ever touched through the lock
+unsafe impl Send for Heap {}
+
+static HEAP: Mutex<Heap> = Mutex::new(Heap {
+    head: ptr::null_mut(),
+    grown: 0,
+    in_use: 0,
+});
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// what to ask `framealloc` for to satisfy `layout` directly, or `None` if it belongs on
+/// the free list.
+fn frames_for(layout: Layout) -> Option<u64> {
+    if layout.size() <= MAX_SMALL_ALLOC && layout.align() <= PAGE_SIZE as usize {
+        return None;
+    }
+    // frames are aligned to their size
+    let size = layout.size().max(layout.align()).next_power_of_two();
+    Some((size as u64).max(PAGE_SIZE))
+}
+
+/// the size of the block on the free list which `layout` takes up.
+fn block_size(layout: Layout) -> usize {
+    align_up(layout.size().max(1), BLOCK_ALIGN)
+}
+
+impl Heap {
+    /// take the first block on the free list which can fit `size` bytes aligned to
+    /// `align`, splitting off whatever's left of it on either side.
+    unsafe fn take_first_fit(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut link: *mut *mut FreeBlock = &mut self.head;
+        while !(*link).is_null() {
+            let block = *link;
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+            let start = align_up(block_start, align);
+            if start + size <= block_end {
+                let next = (*block).next;
+                // the padding in front stays on the list where it was, and the space
+                // after goes right behind it
+                let tail_start = start + size;
+                let tail = if tail_start < block_end {
+                    let tail = tail_start as *mut FreeBlock;
+                    tail.write(FreeBlock { size: block_end - tail_start, next });
+                    tail
+                } else {
+                    next
+                };
+                if start > block_start {
+                    (*block).size = start - block_start;
+                    (*block).next = tail;
+                } else {
+                    *link = tail;
+                }
+                return Some(start as *mut u8);
+            }
+            link = &mut (*block).next;
+        }
+        None
+    }
+
+    /// put `size` bytes at `start` on the free list, merging it with its neighbours.
+    unsafe fn insert(&mut self, start: *mut u8, size: usize) {
+        let start = start as usize;
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = start as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// add enough to the free list from `framealloc` for `size` bytes aligned to `align`.
+    unsafe fn grow(&mut self, size: usize, align: usize) -> bool {
+        let needed = (size + align) as u64;
+        let chunk = needed.next_power_of_two().max(MIN_GROWTH);
+        if chunk > GIGABYTE {
+            return false;
+        }
+        match framealloc::alloc_frame(chunk) {
+            Some(frame) => {
+                self.insert(frame.as_mut(), chunk as usize);
+                self.grown += chunk;
+                true
+            }
+            None => false,
+        }
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Some(frame_size) = frames_for(layout) {
+            if frame_size > GIGABYTE {
+                return ptr::null_mut();
+            }
+            return match framealloc::alloc_frame(frame_size) {
+                Some(frame) => {
+                    self.in_use += frame_size;
+                    frame.as_mut()
+                }
+                None => ptr::null_mut(),
+            };
+        }
+
+        let size = block_size(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+        let found = match self.take_first_fit(size, align) {
+            Some(found) => Some(found),
+            None if self.grow(size, align) => self.take_first_fit(size, align),
+            None => None,
+        };
+        match found {
+            Some(found) => {
+                self.in_use += size as u64;
+                found
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(frame_size) = frames_for(layout) {
+            self.in_use -= frame_size;
+            framealloc::free_frame(kernel_paddr(ptr as u64), frame_size);
+        } else {
+            let size = block_size(layout);
+            self.in_use -= size as u64;
+            self.insert(ptr, size);
+        }
+    }
+
+    /// the bytes on the free list, and how many blocks they're in.
+    fn free_totals(&self) -> (u64, usize) {
+        let mut bytes = 0;
+        let mut blocks = 0;
+        let mut cur = self.head;
+        while !cur.is_null() {
+            unsafe {
+                bytes += (*cur).size as u64;
+                cur = (*cur).next;
+            }
+            blocks += 1;
+        }
+        (bytes, blocks)
+    }
+}
+
+struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    // see `irq::without_interrupts` for why the lock is taken with irqs masked
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        irq::without_interrupts(|| HEAP.lock().alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        irq::without_interrupts(|| HEAP.lock().dealloc(ptr, layout))
+    }
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap;
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "out of memory allocating {} bytes aligned to {}",
+        layout.size(), layout.align(),
+    )
+}
+
+/// print how big the heap is and how much of it is in use.
+pub fn print_stats() {
+    let (in_use, grown, (free, blocks)) = irq::without_interrupts(|| {
+        let heap = HEAP.lock();
+        (heap.in_use, heap.grown, heap.free_totals())
+    });
+    println!("kernel heap:");
+    println!("    {} KiB in use", in_use >> 10);
+    println!("    {} KiB taken for the free list, {} KiB of it free in {} blocks",
+             grown >> 10, free >> 10, blocks);
+}
@@ -0,0 +1,292 @@
+//! the kernel's address space, and turning the mmu on.
+//!
+//! the kernel is linked in the high half, at `KADDR_MIN` plus wherever it's loaded, and
+//! everything but the first few instructions runs there. both halves are 48 bits wide.
+//!
+//! the mmu goes on before anything else, with the boot map: a few static tables which
+//! map the bottom 512 GiB of physical memory at both `paddr` and `KADDR_MIN + paddr`,
+//! the board's mmio as device memory and everything else as ram, since we don't know
+//! yet where ram is. once the frame allocator is up, `init` builds the kernel's real
+//! tables, which map just ram and the board's mmio, at `KADDR_MIN + paddr` through
+//! `TTBR1_EL1`: the linear map.
+//!
+//! the low half is only for getting into the high one. secondary cores come up through
+//! an identity map of the kernel image in `TTBR0_EL1`, and every core turns walks of
+//! `TTBR0_EL1` off with `disable_identity_map` once it's in the high half, which leaves
+//! the low half to userspace.
+
+use crate::asm::{self, dsb};
+use crate::board;
+use crate::memory::{
+    self,
+    addrspace::{self, AddressSpace, Attributes, ENTRIES},
+    physmap::{self, Region, RegionList},
+    Paddr, Vaddr, GIGABYTE,
+};
+use core::{mem, ptr};
+use cortex_a::registers::{ID_AA64MMFR0_EL1, MAIR_EL1, SCTLR_EL1, TCR_EL1};
+use spin::{Mutex, Once};
+use tock_registers::interfaces::{ReadWriteable, Readable};
+
+/// both halves are 48 bits wide.
+const VADDR_BITS: u64 = 48;
+
+/// how many regions we can keep track of while building the kernel's tables.
+const MAX_REGIONS: usize = 16;
+
+/// the bits of `SCTLR_EL1` which armv8.0 says must be set. nothing sets the register
+/// up before us, so we can't keep what's already there.
+const SCTLR_RES1: u64 = (1 << 11) | (1 << 20) | (1 << 22) | (1 << 23) | (1 << 28) | (1 << 29);
+
+/// the size of a block in a level 2 table.
+const BOOT_BLOCK_SIZE: u64 = 2 << 20;
+
+/// how many of the boot map's gigabytes can have mmio in them. those get a table of
+/// 2 MiB blocks each, so that the ram around the mmio doesn't have to be device memory
+/// too.
+const BOOT_SPLIT_GIGABYTES: usize = 4;
+
+#[repr(C, align(4096))]
+/// the boot map's tables. they're built before .bss is zeroed, so they live in a section
+/// of their own.
+struct BootTables {
+    root: [u64; ENTRIES],
+    gigabytes: [u64; ENTRIES],
+    split: [[u64; ENTRIES]; BOOT_SPLIT_GIGABYTES],
+}
+
+#[link_section = ".boot_tables"]
+static mut BOOT_TABLES: BootTables = BootTables {
+    root: [0; ENTRIES],
+    gigabytes: [0; ENTRIES],
+    split: [[0; ENTRIES]; BOOT_SPLIT_GIGABYTES],
+};
+
+#[repr(C)]
+/// the system register values which turn the mmu on with some set of tables.
+///
+/// `enable_mmu` loads these before there's a stack, and with the caches off, so the
+/// layout matters, and so does making sure they're in memory rather than just in the
+/// boot core's cache.
+pub struct MmuConfig {
+    pub mair: u64,
+    pub tcr: u64,
+    pub ttbr0: u64,
+    pub ttbr1: u64,
+    pub sctlr: u64,
+}
+
+const NO_MMU_CONFIG: MmuConfig = MmuConfig {
+    mair: 0,
+    tcr: 0,
+    ttbr0: 0,
+    ttbr1: 0,
+    sctlr: 0,
+};
+
+#[link_section = ".boot_tables"]
+/// the boot map, for the boot core's `enable_mmu`.
+static mut BOOT_MMU_CONFIG: MmuConfig = NO_MMU_CONFIG;
+
+/// the kernel's tables, with the identity map in the low half, for secondary cores.
+/// filled in by `init`, before there are any other cores, and never changed after.
+pub static mut KERNEL_MMU_CONFIG: MmuConfig = NO_MMU_CONFIG;
+
+static KERNEL_SPACE: Once<Mutex<AddressSpace>> = Once::new();
+
+/// the kernel's tables, once `init` has built them. they're walked through
+/// `TTBR1_EL1`, so whatever's mapped at `vaddr` here shows up at `KADDR_MIN + vaddr`.
+///
+/// ram is mapped with blocks, and the kernel's image and stacks are somewhere in there,
+/// so these tables forbid splits: `unmap` and `protect` fail with `WouldSplit` for any
+/// range which doesn't start and end on the edges of blocks.
+pub fn kernel_space() -> &'static Mutex<AddressSpace> {
+    KERNEL_SPACE.get().expect("the kernel's tables haven't been built yet")
+}
+
+/// the system register values which turn the mmu on with `ttbr0` and `ttbr1` as the
+/// roots of the two halves.
+fn mmu_config(ttbr0: Paddr, ttbr1: Paddr) -> MmuConfig {
+    let mair = MAIR_EL1::Attr0_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
+        + MAIR_EL1::Attr0_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
+        + MAIR_EL1::Attr1_Device::nonGathering_nonReordering_EarlyWriteAck;
+
+    // we can't use more physical address bits than the tables can hold
+    let pa_range = ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange).min(0b101);
+    let tcr = TCR_EL1::T0SZ.val(64 - VADDR_BITS)
+        + TCR_EL1::T1SZ.val(64 - VADDR_BITS)
+        + TCR_EL1::TG0::KiB_4
+        + TCR_EL1::TG1::KiB_4
+        + TCR_EL1::SH0::Inner
+        + TCR_EL1::SH1::Inner
+        + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::EPD0::EnableTTBR0Walks
+        + TCR_EL1::EPD1::EnableTTBR1Walks
+        + TCR_EL1::A1::TTBR0
+        + TCR_EL1::IPS.val(pa_range);
+
+    // little endian, no alignment checks, and everything else off
+    let sctlr = SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable;
+
+    MmuConfig {
+        mair: mair.value,
+        tcr: tcr.value,
+        ttbr0: ttbr0.0,
+        ttbr1: ttbr1.0,
+        sctlr: sctlr.value | SCTLR_RES1,
+    }
+}
+
+#[link_section = ".text.boot"]
+/// build the boot map, and return what `enable_mmu` needs to turn it on.
+///
+/// this runs with the mmu off, at the kernel's physical address, so it mustn't use any
+/// absolute addresses: no trait objects, no function pointers, and no formatting, which
+/// means no panics. statics are fine, since they're found relative to the pc.
+pub unsafe extern "C" fn build_boot_map() -> *const MmuConfig {
+    let tables = &mut BOOT_TABLES;
+    // volatile, so that this doesn't turn into a call to `memset`
+    for entry in tables.root.iter_mut() {
+        ptr::write_volatile(entry, 0);
+    }
+
+    let has_mmio = |start: u64, size: u64| {
+        board::memory::DEVICES.iter().any(|&(base, len)| base < start + size && start < base + len)
+    };
+    let mut split = 0;
+    for (i, entry) in tables.gigabytes.iter_mut().enumerate() {
+        let start = i as u64 * GIGABYTE;
+        *entry = if !has_mmio(start, GIGABYTE) {
+            addrspace::block_descriptor(Paddr(start), 1, Attributes::KERNEL)
+        } else if split < BOOT_SPLIT_GIGABYTES {
+            let table = &mut tables.split[split];
+            split += 1;
+            for (j, entry) in table.iter_mut().enumerate() {
+                let start = start + j as u64 * BOOT_BLOCK_SIZE;
+                let attrs = if has_mmio(start, BOOT_BLOCK_SIZE) {
+                    Attributes::DEVICE
+                } else {
+                    Attributes::KERNEL
+                };
+                *entry = addrspace::block_descriptor(Paddr(start), 2, attrs);
+            }
+            addrspace::table_descriptor(Paddr(table.as_ptr() as u64))
+        } else {
+            // better that some ram be uncached than that some mmio be cached
+            addrspace::block_descriptor(Paddr(start), 1, Attributes::DEVICE)
+        };
+    }
+
+    // both halves index the tables with bits 47:0, so one root does for both
+    tables.root[0] = addrspace::table_descriptor(Paddr(tables.gigabytes.as_ptr() as u64));
+    let root = Paddr(tables.root.as_ptr() as u64);
+    BOOT_MMU_CONFIG = mmu_config(root, root);
+    &BOOT_MMU_CONFIG
+}
+
+/// build the kernel's tables and move the boot core onto them. needs the frame
+/// allocator, and has to happen on the boot core before any other core is up.
+pub unsafe fn init() {
+    let mut space = AddressSpace::new().expect("out of memory for the kernel's tables");
+
+    let mut devices = RegionList::<MAX_REGIONS>::new();
+    for &(start, size) in board::memory::DEVICES {
+        devices.insert(Region::new(Paddr(start), size).page_align_outward());
+    }
+    // if the board's fallback memory map covers a device, the device wins
+    let mut ram = RegionList::<MAX_REGIONS>::new();
+    physmap::for_each_ram_region(|region| ram.insert(region.page_align_outward()));
+    for device in devices.iter() {
+        ram.remove(device);
+    }
+
+    let mapped = ram.iter().map(|region| (region, Attributes::KERNEL))
+        .chain(devices.iter().map(|region| (region, Attributes::DEVICE)));
+    for (region, attrs) in mapped {
+        let Region { start, .. } = region;
+        if let Err(err) = space.map(Vaddr(start.0), start, region.size(), attrs) {
+            panic!("couldn't map {:x} for the kernel: {}", start, err);
+        }
+    }
+    space.forbid_splits();
+
+    // secondary cores turn their mmus on while they're running from the image's
+    // physical address, so they need it mapped there too
+    let mut identity = AddressSpace::new().expect("out of memory for the identity map");
+    let image = Region { start: memory::text_start(), end: memory::kernel_end() }
+        .page_align_outward();
+    if let Err(err) = identity.map(Vaddr(image.start.0), image.start, image.size(), Attributes::KERNEL) {
+        panic!("couldn't map the kernel image at {:x}: {}", image.start, err);
+    }
+
+    KERNEL_MMU_CONFIG = mmu_config(identity.root(), space.root());
+    // secondary cores read it with their caches off
+    asm::clean_dcache_range(&KERNEL_MMU_CONFIG as *const MmuConfig as u64,
+                            mem::size_of::<MmuConfig>() as u64);
+    // every secondary core needs it to start, so it's never freed
+    mem::forget(identity);
+
+    let root = space.root();
+    KERNEL_SPACE.call_once(|| Mutex::new(space));
+    dsb::sy();
+    // go through the boot map's identity map, so that nothing is fetched through
+    // `TTBR1_EL1` while it changes
+    let replace: unsafe extern "C" fn(u64) =
+        mem::transmute(memory::kernel_paddr(replace_ttbr1 as *const () as u64).0 as usize);
+    replace(root.0);
+    disable_identity_map();
+}
+
+#[link_section = ".text.boot"]
+#[naked]
+/// point `TTBR1_EL1` at `root`, and throw away anything cached from the old tables.
+/// must be called at its physical address.
+unsafe extern "C" fn replace_ttbr1(_root: u64) {
+    // root is in x0
+    asm!(
+        "msr ttbr1_el1, x0",
+        "isb",
+        "tlbi vmalle1",
+        "dsb nsh",
+        "isb",
+        "ret",
+
+        options(noreturn),
+    )
+}
+
+/// stop the calling core walking `TTBR0_EL1`, once it's in the high half for good.
+pub fn disable_identity_map() {
+    TCR_EL1.modify(TCR_EL1::EPD0::DisableTTBR0Walks);
+    unsafe { asm!("isb", "tlbi vmalle1", "dsb nsh", "isb", options(nostack)) };
+}
+
+#[link_section = ".text.boot"]
+#[naked]
+/// load `config` into the calling core's system registers, which turns its mmu and
+/// caches on. doesn't touch the stack, so it can be called before there is one.
+pub unsafe extern "C" fn enable_mmu(_config: *const MmuConfig) {
+    // config is in x0
+    asm!(
+        "ldp x9, x10, [x0]",
+        "msr mair_el1, x9",
+        "msr tcr_el1, x10",
+        "ldp x9, x10, [x0, #16]",
+        "msr ttbr0_el1, x9",
+        "msr ttbr1_el1, x10",
+        // nothing from before is worth keeping
+        "tlbi vmalle1",
+        "ic iallu",
+        "dsb nsh",
+        "isb",
+        "ldr x9, [x0, #32]",
+        "msr sctlr_el1, x9",
+        "isb",
+        "ret",
+
+        options(noreturn),
+    )
+}
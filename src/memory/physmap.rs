@@ -0,0 +1,246 @@
+//! the physical memory map: which ranges of physical addresses are ram.
+//!
+//! the ranges come from the `/memory` nodes of the device tree when we have one, or
+//! from `board::memory::RAM` when we don't.
+
+use crate::memory::{Paddr, PAGE_SIZE};
+use crate::fdt::DeviceTree;
+use crate::board;
+use core::fmt;
+use spin::Mutex;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// the half-open range of physical addresses `start..end`.
+pub struct Region {
+    pub start: Paddr,
+    pub end: Paddr,
+}
+
+impl Region {
+    pub fn new(start: Paddr, size: u64) -> Self {
+        Region { start, end: Paddr(start.0 + size) }
+    }
+
+    /// the number of bytes in the region, or 0 if it's empty.
+    pub fn size(&self) -> u64 {
+        self.end.0.saturating_sub(self.start.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    pub fn contains(&self, addr: Paddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    /// the smallest region of whole pages which covers `self`.
+    pub fn page_align_outward(&self) -> Region {
+        Region {
+            start: Paddr(self.start.0 & !(PAGE_SIZE - 1)),
+            end: Paddr((self.end.0 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)),
+        }
+    }
+
+    /// the largest region of whole pages which `self` covers, which may be empty.
+    pub fn page_align_inward(&self) -> Region {
+        Region {
+            start: Paddr((self.start.0 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)),
+            end: Paddr(self.end.0 & !(PAGE_SIZE - 1)),
+        }
+    }
+
+    /// true if the two regions overlap or abut, i.e. their union is a single region.
+    pub fn touches(&self, other: &Region) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// the part of `self` that's also in `other`, which may be empty.
+    pub fn intersection(&self, other: &Region) -> Region {
+        Region {
+            start: self.start.max(other.start),
+            end: self.end.min(other.end),
+        }
+    }
+}
+
+impl fmt::LowerHex for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:#014x}..{:#014x})", self.start.0, self.end.0)
+    }
+}
+
+const EMPTY_REGION: Region = Region { start: Paddr(0), end: Paddr(0) };
+
+/// a fixed-capacity list of disjoint regions, kept sorted by address. regions which
+/// overlap or abut are merged on insertion.
+pub struct RegionList<const N: usize> {
+    regions: [Region; N],
+    len: usize,
+}
+
+impl<const N: usize> RegionList<N> {
+    pub const fn new() -> Self {
+        RegionList { regions: [EMPTY_REGION; N], len: 0 }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions[..self.len].iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// add `new` to the list, merging it with any regions it touches.
+    ///
+    /// panics if the list is full.
+    pub fn insert(&mut self, mut new: Region) {
+        if new.is_empty() {
+            return;
+        }
+        // absorb every region which touches `new`, then slot the union in where it
+        // belongs.
+        let mut i = 0;
+        while i < self.len {
+            let r = self.regions[i];
+            if r.touches(&new) {
+                new.start = new.start.min(r.start);
+                new.end = new.end.max(r.end);
+                self.remove_index(i);
+            } else {
+                i += 1;
+            }
+        }
+        assert!(self.len < N, "RegionList overflowed its {} entries", N);
+        let pos = self.regions[..self.len]
+            .iter()
+            .position(|r| r.start > new.start)
+            .unwrap_or(self.len);
+        self.regions.copy_within(pos..self.len, pos + 1);
+        self.regions[pos] = new;
+        self.len += 1;
+    }
+
+    /// take `hole` out of the list, trimming or splitting any regions it overlaps.
+    ///
+    /// panics if splitting a region overflows the list.
+    pub fn remove(&mut self, hole: Region) {
+        let mut i = 0;
+        while i < self.len {
+            let r = self.regions[i];
+            if !r.overlaps(&hole) {
+                i += 1;
+                continue;
+            }
+            self.remove_index(i);
+            let below = Region { start: r.start, end: hole.start };
+            let above = Region { start: hole.end, end: r.end };
+            // both pieces are disjoint from everything else in the list, so `insert`
+            // won't merge them away, and `i` will point past them when it's done.
+            for piece in [below, above].iter().filter(|p| !p.is_empty()) {
+                self.insert(*piece);
+                i += 1;
+            }
+        }
+    }
+
+    /// true if any part of `region` is in the list.
+    pub fn overlaps(&self, region: &Region) -> bool {
+        self.iter().any(|r| r.overlaps(region))
+    }
+
+    /// call `f` on each maximal piece of `region` which is not in the list, in ascending
+    /// order.
+    pub fn for_each_gap<F: FnMut(Region)>(&self, region: Region, mut f: F) {
+        let mut start = region.start;
+        for r in self.iter() {
+            if r.end <= start {
+                continue;
+            }
+            if r.start >= region.end {
+                break;
+            }
+            let gap = Region { start, end: r.start.min(region.end) };
+            if !gap.is_empty() {
+                f(gap);
+            }
+            start = start.max(r.end);
+        }
+        let gap = Region { start, end: region.end };
+        if !gap.is_empty() {
+            f(gap);
+        }
+    }
+
+    fn remove_index(&mut self, i: usize) {
+        self.regions.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+    }
+}
+
+/// how many discontiguous ram regions we can keep track of.
+pub const MAX_RAM_REGIONS: usize = 16;
+
+static PHYS_MAP: Mutex<RegionList<MAX_RAM_REGIONS>> = Mutex::new(RegionList::new());
+
+/// fill the memory map from the `/memory` nodes of `tree`, or from the board's defaults
+/// if there's no tree or it doesn't describe any memory.
+pub fn init(tree: Option<&DeviceTree>) {
+    let mut map = PHYS_MAP.try_lock()
+        .expect("PHYS_MAP already locked when initializing.");
+
+    if let Some(tree) = tree {
+        let memory_nodes = tree.root().children().filter(|node| {
+            node.is_enabled()
+                && (node.base_name() == "memory"
+                    || node.property("device_type").and_then(|p| p.as_str()) == Some("memory"))
+        });
+        for node in memory_nodes {
+            for reg in node.reg() {
+                map.insert(Region::new(Paddr(reg.address), reg.size));
+            }
+        }
+    }
+
+    if map.is_empty() {
+        for &(start, size) in board::memory::RAM {
+            map.insert(Region::new(Paddr(start), size));
+        }
+    }
+}
+
+/// call `f` on each ram region, in ascending order.
+pub fn for_each_ram_region<F: FnMut(Region)>(f: F) {
+    PHYS_MAP.lock().iter().for_each(f)
+}
+
+/// call `f` on each maximal piece of `region` which isn't ram, in ascending order. there
+/// are at most `MAX_RAM_REGIONS + 1` of them.
+pub fn for_each_gap<F: FnMut(Region)>(region: Region, f: F) {
+    PHYS_MAP.lock().for_each_gap(region, f)
+}
+
+/// record that `region` is ram, calling `f` on each part of it which wasn't already.
+/// this doesn't make it available to allocate; see `framealloc::add_memory`.
+pub fn add_ram<F: FnMut(Region)>(region: Region, f: F) {
+    let mut map = PHYS_MAP.lock();
+    map.for_each_gap(region, f);
+    map.insert(region)
+}
+
+/// record that `region` is no longer ram.
+pub fn remove_ram(region: Region) {
+    PHYS_MAP.lock().remove(region)
+}
+
+/// the highest physical address which is ram.
+pub fn max_phys_addr() -> Paddr {
+    let map = PHYS_MAP.lock();
+    let last = map.iter().last().expect("the physical memory map is empty");
+    Paddr(last.end.0 - 1)
+}
@@ -0,0 +1,368 @@
+//! slab caches, for lots of small objects of the same size.
+//!
+//! a cache carves blocks from `framealloc` into slabs of equal-sized objects. each slab
+//! is aligned to its size and starts with a `Slab` header, so freeing an object finds its
+//! slab by rounding the address down. the header is followed by a stack of the indices
+//! of the slab's free objects, and then by the objects themselves, so free objects are
+//! never written to.
+//!
+//! that means a cache can have a constructor, which runs on each object once, when its
+//! slab is made, rather than on every allocation. objects have to go back to the cache
+//! in their constructed state, and allocating one is just popping an index.
+//!
+//! `alloc` and `free` serve any size up to `MAX_SIZE` from a set of power-of-two caches.
+
+use crate::irq;
+use crate::memory::framealloc::{self, FrameState, NO_OWNER};
+use crate::memory::{kernel_paddr, Pointer, PAGE_SIZE};
+use crate::println;
+use core::mem;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// slabs grow until they hold at least this many objects, or hit `MAX_SLAB_SIZE`.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+const MAX_SLAB_SIZE: usize = 64 * PAGE_SIZE as usize;
+
+/// how many caches `print_stats` can keep track of.
+const MAX_CACHES: usize = 32;
+
+/// the biggest size `alloc` takes.
+pub const MAX_SIZE: usize = 2048;
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[repr(C)]
+/// the start of each slab. followed by `capacity` `u16` indices of free objects, the
+/// first `free_count` of which are valid, and then by the objects.
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free_count: usize,
+}
+
+impl Slab {
+    unsafe fn free_indices(slab: *mut Slab) -> *mut u16 {
+        slab.add(1) as *mut u16
+    }
+}
+
+#[derive(Copy, Clone)]
+/// how a cache's slabs are laid out.
+struct Geometry {
+    slab_size: usize,
+    /// objects per slab.
+    capacity: usize,
+    /// from the start of the slab to the first object.
+    objects_offset: usize,
+    /// from one object to the next.
+    stride: usize,
+}
+
+impl Geometry {
+    fn new(size: usize, align: usize) -> Geometry {
+        let stride = align_up(size.max(1), align);
+        let mut slab_size = PAGE_SIZE as usize;
+        loop {
+            let header = mem::size_of::<Slab>();
+            let mut capacity = (slab_size - header) / (stride + mem::size_of::<u16>());
+            capacity = capacity.min(u16::MAX as usize + 1);
+            let objects_offset = |capacity| align_up(header + 2 * capacity, align);
+            while capacity > 0 && objects_offset(capacity) + capacity * stride > slab_size {
+                capacity -= 1;
+            }
+            if capacity >= MIN_OBJECTS_PER_SLAB || slab_size == MAX_SLAB_SIZE {
+                assert!(capacity > 0, "{}-byte objects are too big for a slab", size);
+                return Geometry {
+                    slab_size,
+                    capacity,
+                    objects_offset: objects_offset(capacity),
+                    stride,
+                };
+            }
+            slab_size *= 2;
+        }
+    }
+}
+
+/// an intrusive doubly-linked list of slabs.
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const EMPTY: SlabList = SlabList { head: ptr::null_mut(), len: 0 };
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        let Slab { prev, next, .. } = *slab;
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        self.len -= 1;
+    }
+}
+
+struct Slabs {
+    /// worked out the first time the cache needs a slab.
+    geometry: Option<Geometry>,
+    /// slabs with some objects allocated and some free.
+    partial: SlabList,
+    full: SlabList,
+    /// one slab with nothing allocated, kept around so that a cache which hovers around
+    /// a slab boundary doesn't keep going back to `framealloc`. any more go back to it.
+    empty: *mut Slab,
+    in_use: usize,
+    allocs: u64,
+    frees: u64,
+}
+
+// the slabs are only ever touched through the cache's lock
+unsafe impl Send for Slabs {}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CacheStats {
+    pub object_size: usize,
+    pub slab_size: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_free: usize,
+    /// since the cache was made.
+    pub allocs: u64,
+    pub frees: u64,
+}
+
+/// a cache of objects of one size, which lives forever.
+pub struct ObjectCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    constructor: Option<fn(*mut u8)>,
+    slabs: Mutex<Slabs>,
+    registered: AtomicBool,
+}
+
+impl ObjectCache {
+    /// a cache of `size`-byte objects aligned to `align`, which has to be a power of
+    /// two. `constructor`, if there is one, runs with the cache locked and irqs masked,
+    /// so it mustn't allocate from the same cache.
+    pub const fn new(
+        name: &'static str,
+        size: usize,
+        align: usize,
+        constructor: Option<fn(*mut u8)>,
+    ) -> ObjectCache {
+        assert!(align.is_power_of_two(), "slab alignment isn't a power of two");
+        ObjectCache {
+            name,
+            size,
+            align,
+            constructor,
+            slabs: Mutex::new(Slabs {
+                geometry: None,
+                partial: SlabList::EMPTY,
+                full: SlabList::EMPTY,
+                empty: ptr::null_mut(),
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            }),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // see `irq::without_interrupts` for why the lock is taken with irqs masked
+    fn with_slabs<F: FnOnce(&mut Slabs) -> R, R>(&self, f: F) -> R {
+        irq::without_interrupts(|| f(&mut self.slabs.lock()))
+    }
+
+    /// a new slab, with every object constructed and free.
+    unsafe fn new_slab(&self, geometry: Geometry) -> Option<*mut Slab> {
+        let size = geometry.slab_size as u64;
+        let frame = framealloc::alloc_frame_for(size, FrameState::Slab, NO_OWNER)?;
+        let slab: *mut Slab = frame.as_mut();
+        slab.write(Slab {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free_count: geometry.capacity,
+        });
+        // backwards, so the first object is the first to go
+        let free = Slab::free_indices(slab);
+        for i in 0..geometry.capacity {
+            free.add(i).write((geometry.capacity - 1 - i) as u16);
+        }
+        if let Some(constructor) = self.constructor {
+            for i in 0..geometry.capacity {
+                let object = (slab as *mut u8).add(geometry.objects_offset + i * geometry.stride);
+                constructor(object);
+            }
+        }
+        Some(slab)
+    }
+
+    /// an object from the cache, or `None` if there's no memory for another slab.
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            register(self);
+        }
+        self.with_slabs(|slabs| unsafe {
+            let geometry = *slabs.geometry
+                .get_or_insert_with(|| Geometry::new(self.size, self.align));
+            let slab = if !slabs.partial.head.is_null() {
+                slabs.partial.head
+            } else {
+                let slab = if !slabs.empty.is_null() {
+                    mem::replace(&mut slabs.empty, ptr::null_mut())
+                } else {
+                    self.new_slab(geometry)?
+                };
+                slabs.partial.push(slab);
+                slab
+            };
+
+            (*slab).free_count -= 1;
+            let index = *Slab::free_indices(slab).add((*slab).free_count) as usize;
+            if (*slab).free_count == 0 {
+                slabs.partial.remove(slab);
+                slabs.full.push(slab);
+            }
+            slabs.in_use += 1;
+            slabs.allocs += 1;
+
+            let object = (slab as *mut u8).add(geometry.objects_offset + index * geometry.stride);
+            Some(NonNull::new_unchecked(object))
+        })
+    }
+
+    /// give `object` back to the cache, in its constructed state.
+    ///
+    /// unsafe because `object` has to have come from this cache's `alloc`, and nothing
+    /// can use it after.
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        self.with_slabs(|slabs| {
+            let geometry = slabs.geometry
+                .expect("freeing to a slab cache which never allocated");
+            let object = object.as_ptr() as usize;
+            let slab = (object & !(geometry.slab_size - 1)) as *mut Slab;
+            let offset = object - slab as usize - geometry.objects_offset;
+            assert!(offset % geometry.stride == 0, "freeing {:#x}, which isn't an object in {}",
+                    object, self.name);
+            let index = offset / geometry.stride;
+
+            if (*slab).free_count == 0 {
+                slabs.full.remove(slab);
+                slabs.partial.push(slab);
+            }
+            *Slab::free_indices(slab).add((*slab).free_count) = index as u16;
+            (*slab).free_count += 1;
+            slabs.in_use -= 1;
+            slabs.frees += 1;
+
+            if (*slab).free_count == geometry.capacity {
+                slabs.partial.remove(slab);
+                if slabs.empty.is_null() {
+                    slabs.empty = slab;
+                } else {
+                    framealloc::free_frame(kernel_paddr(slab as u64), geometry.slab_size as u64);
+                }
+            }
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.with_slabs(|slabs| {
+            let slab_size = slabs.geometry.map_or(0, |g| g.slab_size);
+            let capacity = slabs.geometry.map_or(0, |g| g.capacity);
+            let slab_count = slabs.partial.len + slabs.full.len + (!slabs.empty.is_null()) as usize;
+            CacheStats {
+                object_size: self.size,
+                slab_size,
+                slabs: slab_count,
+                objects_in_use: slabs.in_use,
+                objects_free: slab_count * capacity - slabs.in_use,
+                allocs: slabs.allocs,
+                frees: slabs.frees,
+            }
+        })
+    }
+}
+
+static CACHES: Mutex<[Option<&'static ObjectCache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+fn register(cache: &'static ObjectCache) {
+    irq::without_interrupts(|| {
+        let mut caches = CACHES.lock();
+        let slot = caches.iter_mut().find(|slot| slot.is_none())
+            .expect("too many slab caches to keep track of");
+        *slot = Some(cache);
+    });
+}
+
+static SIZE_CACHES: [ObjectCache; 8] = [
+    ObjectCache::new("size-16", 16, 16, None),
+    ObjectCache::new("size-32", 32, 32, None),
+    ObjectCache::new("size-64", 64, 64, None),
+    ObjectCache::new("size-128", 128, 128, None),
+    ObjectCache::new("size-256", 256, 256, None),
+    ObjectCache::new("size-512", 512, 512, None),
+    ObjectCache::new("size-1024", 1024, 1024, None),
+    ObjectCache::new("size-2048", 2048, 2048, None),
+];
+
+fn size_cache(size: usize) -> &'static ObjectCache {
+    assert!(size <= MAX_SIZE, "{} bytes is too big for a slab size cache", size);
+    let size = size.max(SIZE_CACHES[0].size).next_power_of_two();
+    let index = size.trailing_zeros() - SIZE_CACHES[0].size.trailing_zeros();
+    &SIZE_CACHES[index as usize]
+}
+
+/// `size` bytes, aligned to `size` rounded up to a power of two, for any `size` up to
+/// `MAX_SIZE`.
+pub fn alloc(size: usize) -> Option<NonNull<u8>> {
+    size_cache(size).alloc()
+}
+
+/// give back `object`, which `alloc(size)` returned.
+///
+/// unsafe because nothing can use `object` after.
+pub unsafe fn free(object: NonNull<u8>, size: usize) {
+    size_cache(size).free(object)
+}
+
+/// print every cache which has been used, and how full it is.
+pub fn print_stats() {
+    println!("slab caches:");
+    println!("    {:<16} {:>6} {:>6} {:>6} {:>8} {:>8} {:>10} {:>10}",
+             "name", "size", "slabs", "KiB", "in use", "free", "allocs", "frees");
+    let caches = irq::without_interrupts(|| *CACHES.lock());
+    for cache in caches.iter().flatten() {
+        let stats = cache.stats();
+        println!("    {:<16} {:>6} {:>6} {:>6} {:>8} {:>8} {:>10} {:>10}",
+                 cache.name(), stats.object_size, stats.slabs,
+                 (stats.slabs * stats.slab_size) >> 10, stats.objects_in_use,
+                 stats.objects_free, stats.allocs, stats.frees);
+    }
+}
@@ -0,0 +1,94 @@
+//! per-cpu data, reachable through `TPIDR_EL1`.
+//!
+//! every core gets one `PerCpu` out of a static array, and points its `TPIDR_EL1` at
+//! it as soon as it has a stack. the usual way in is the `percpu!` macro in `asm.rs`.
+
+use crate::smp::MAX_CORES;
+use core::cell::Cell;
+use core::ptr;
+use cortex_a::registers::TPIDR_EL1;
+use tock_registers::interfaces::{Readable, Writeable};
+
+/// things each core counts about itself.
+pub struct Counters {
+    pub irqs: Cell<u64>,
+    pub exceptions: Cell<u64>,
+    pub timer_ticks: Cell<u64>,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            irqs: Cell::new(0),
+            exceptions: Cell::new(0),
+            timer_ticks: Cell::new(0),
+        }
+    }
+
+    /// add one to `counter`.
+    pub fn bump(counter: &Cell<u64>) {
+        counter.set(counter.get().wrapping_add(1));
+    }
+}
+
+#[repr(C)]
+pub struct PerCpu {
+    core_id: Cell<usize>,
+    /// the thread running on this core. we don't have threads yet, so this is always
+    /// null.
+    pub current_thread: Cell<*mut ()>,
+    /// how many irq handlers deep this core is.
+    pub irq_depth: Cell<usize>,
+    pub counters: Counters,
+}
+
+// each `PerCpu` is only ever touched by the core which owns it, and only through its
+// own `TPIDR_EL1`, so none of the `Cell`s are ever shared.
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            core_id: Cell::new(0),
+            current_thread: Cell::new(ptr::null_mut()),
+            irq_depth: Cell::new(0),
+            counters: Counters::new(),
+        }
+    }
+
+    /// our index among the cores, as assigned by `smp`. the boot core is 0.
+    pub fn core_id(&self) -> usize {
+        self.core_id.get()
+    }
+
+    pub fn in_irq(&self) -> bool {
+        self.irq_depth.get() > 0
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_PERCPU: PerCpu = PerCpu::new();
+
+static PERCPU: [PerCpu; MAX_CORES] = [NEW_PERCPU; MAX_CORES];
+
+/// claim `PERCPU[core]` for the calling core.
+///
+/// # safety
+///
+/// must be called exactly once on each core, before anything on that core uses
+/// `this`, and no two cores may claim the same index.
+pub unsafe fn init(core: usize) {
+    let percpu = &PERCPU[core];
+    percpu.core_id.set(core);
+    TPIDR_EL1.set(percpu as *const PerCpu as u64);
+}
+
+/// the calling core's `PerCpu`.
+///
+/// the reference must not be sent to another core, which the compiler can't check for
+/// us since we don't have threads yet.
+pub fn this() -> &'static PerCpu {
+    let ptr = TPIDR_EL1.get() as *const PerCpu;
+    debug_assert!(!ptr.is_null(), "percpu::this called before percpu::init");
+    unsafe { &*ptr }
+}
@@ -0,0 +1,114 @@
+//! calls into the power state coordination interface, which the firmware (or qemu, or
+//! a hypervisor) implements at a higher exception level.
+//!
+//! see arm den 0022, the psci specification. we only need the bits for turning cores
+//! on.
+
+use crate::fdt::DeviceTree;
+use crate::memory::Paddr;
+use crate::board;
+use core::fmt;
+use spin::Once;
+
+const CPU_ON_64: u32 = 0xc400_0003;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// which instruction reaches the psci implementation.
+pub enum Conduit {
+    Hvc,
+    Smc,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(i64),
+}
+
+impl PsciError {
+    fn from_return(ret: i64) -> Result<i64, PsciError> {
+        Err(match ret {
+            -1 => PsciError::NotSupported,
+            -2 => PsciError::InvalidParameters,
+            -3 => PsciError::Denied,
+            -4 => PsciError::AlreadyOn,
+            -5 => PsciError::OnPending,
+            -6 => PsciError::InternalFailure,
+            -7 => PsciError::NotPresent,
+            -8 => PsciError::Disabled,
+            -9 => PsciError::InvalidAddress,
+            ret if ret < 0 => PsciError::Unknown(ret),
+            ret => return Ok(ret),
+        })
+    }
+}
+
+impl fmt::Display for PsciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PsciError::Unknown(ret) => write!(f, "unknown psci error {}", ret),
+            e => fmt::Debug::fmt(e, f),
+        }
+    }
+}
+
+static CONDUIT: Once<Conduit> = Once::new();
+
+/// pick the conduit named by the device tree's `/psci` node, or the board's default.
+pub fn init(tree: Option<&DeviceTree>) {
+    let method = tree
+        .and_then(|tree| tree.find_node("/psci"))
+        .and_then(|psci| psci.property("method"))
+        .and_then(|method| method.as_str());
+    let conduit = match method {
+        Some("hvc") => Conduit::Hvc,
+        Some("smc") => Conduit::Smc,
+        _ => board::smp::PSCI_CONDUIT,
+    };
+    CONDUIT.call_once(|| conduit);
+}
+
+fn conduit() -> Conduit {
+    *CONDUIT.get().expect("psci::init was never called")
+}
+
+/// make an smc calling convention call with up to three arguments.
+fn call(function: u32, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let ret: i64;
+    unsafe {
+        match conduit() {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inlateout("x0") function as u64 => ret,
+                in("x1") arg0,
+                in("x2") arg1,
+                in("x3") arg2,
+                clobber_abi("C"),
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inlateout("x0") function as u64 => ret,
+                in("x1") arg0,
+                in("x2") arg1,
+                in("x3") arg2,
+                clobber_abi("C"),
+            ),
+        }
+    }
+    ret
+}
+
+/// power on the core whose affinity fields are `mpidr`. it will start executing at
+/// `entry`, with the mmu off, at the exception level we're at now, with
+/// `context_id` in x0.
+pub fn cpu_on(mpidr: u64, entry: Paddr, context_id: u64) -> Result<(), PsciError> {
+    PsciError::from_return(call(CPU_ON_64, mpidr, u64::from(entry), context_id)).map(|_| ())
+}
@@ -0,0 +1,71 @@
+//! a fixed-size fifo, for buffering between drivers and their irq handlers.
+//!
+//! there's no synchronization in here; whoever owns the ring is expected to keep it
+//! behind the same lock as the device it buffers for.
+
+use core::mem::MaybeUninit;
+
+pub struct RingBuffer<T, const N: usize> {
+    buf: [MaybeUninit<T>; N],
+    /// index of the oldest element.
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    const EMPTY: MaybeUninit<T> = MaybeUninit::uninit();
+
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: [Self::EMPTY; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+// `Copy`, so that overwriting or forgetting an element never needs to drop it
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// add `value` at the back, or hand it back if there's no room.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.buf[(self.head + self.len) % N] = MaybeUninit::new(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// take the element at the front.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        // everything from `head` for `len` elements has been written by `push`
+        let value = unsafe { self.buf[self.head].as_ptr().read() };
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+}
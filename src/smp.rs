@@ -0,0 +1,183 @@
+//! bringing up the secondary cores.
+//!
+//! each core gets an index: the boot core is 0, and the others are numbered in the
+//! order the device tree (or the board, if there's no device tree) lists them. a
+//! secondary core finds its index by looking its mpidr up in `CORE_MPIDRS`, then takes
+//! its stack from `SECONDARY_STACKS`, so both have to be filled in before it's released.
+
+use crate::fdt::DeviceTree;
+use crate::memory::{self, framealloc, Paddr, Pointer, PAGE_SIZE};
+use crate::time::{self, Wait};
+use crate::irq::{self, Irq, SgiTarget};
+use crate::{asm, board, boot, percpu, println, psci, sleep_forever};
+use cortex_a::registers::MPIDR_EL1;
+use core::mem;
+use core::time::Duration;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tock_registers::interfaces::Readable;
+
+pub const MAX_CORES: usize = 8;
+
+pub const SECONDARY_STACK_SIZE: u64 = 4 * PAGE_SIZE;
+
+/// the affinity fields of the mpidr: aff3, aff2, aff1 and aff0.
+pub const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+/// how long to wait for a released core to check in before giving up on it.
+const CHECK_IN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// the mpidr affinity of each core, indexed by core number. an unused slot is
+/// `u64::MAX`, which no mpidr can be once masked.
+pub static CORE_MPIDRS: [AtomicU64; MAX_CORES] = [EMPTY_MPIDR; MAX_CORES];
+
+/// the initial stack pointer of each secondary core, indexed by core number.
+pub static SECONDARY_STACKS: [AtomicU64; MAX_CORES] = [ZERO; MAX_CORES];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_MPIDR: AtomicU64 = AtomicU64::new(u64::MAX);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// the sgi `stop_others` sends.
+const STOP_SGI: Irq = 0;
+
+/// how many cores have made it to `secondary_main`, plus the boot core.
+static CORES_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// how a secondary core gets released from the firmware.
+pub enum EnableMethod {
+    /// call psci `CPU_ON`.
+    Psci,
+    /// write the entry point to `release_addr` and `sev`; the core is spinning on it.
+    SpinTable { release_addr: u64 },
+}
+
+pub fn current_mpidr() -> u64 {
+    MPIDR_EL1.get() & MPIDR_AFFINITY_MASK
+}
+
+/// the number of cores which are up and running.
+pub fn cores_online() -> usize {
+    CORES_ONLINE.load(Ordering::Acquire)
+}
+
+/// call `f` with the mpidr and enable method of each core the device tree describes,
+/// or the board's list of cores if there's no device tree.
+fn for_each_cpu<F: FnMut(u64, Option<EnableMethod>)>(tree: Option<&DeviceTree>, mut f: F) {
+    let cpus = tree.and_then(|tree| tree.find_node("/cpus"));
+    if let Some(cpus) = cpus {
+        let cpu_nodes = cpus.children().filter(|node| {
+            node.base_name() == "cpu" && node.is_enabled()
+        });
+        for cpu in cpu_nodes {
+            let mpidr = match cpu.reg().next() {
+                Some(reg) => reg.address,
+                None => continue,
+            };
+            let method = match cpu.property("enable-method").and_then(|p| p.as_str()) {
+                Some("psci") => Some(EnableMethod::Psci),
+                Some("spin-table") => cpu.property("cpu-release-addr")
+                    .and_then(|p| p.as_u64())
+                    .map(|release_addr| EnableMethod::SpinTable { release_addr }),
+                _ => None,
+            };
+            f(mpidr, method);
+        }
+    } else {
+        for &(mpidr, method) in board::smp::CPUS {
+            f(mpidr, Some(method));
+        }
+    }
+}
+
+/// stop every other core where it is, so that they don't carry on around a panic. does
+/// nothing until there's an interrupt controller to send the sgi with.
+pub fn stop_others() {
+    if irq::is_initialized() && cores_online() > 1 {
+        irq::send_sgi(STOP_SGI, SgiTarget::AllOthers);
+    }
+}
+
+fn handle_stop(_sgi: Irq) {
+    sleep_forever()
+}
+
+/// release every secondary core we know how to, and wait for each to check in.
+pub fn start_secondaries(tree: Option<&DeviceTree>) {
+    psci::init(tree);
+    if irq::is_initialized() {
+        irq::register_handler(STOP_SGI, handle_stop);
+    }
+
+    let boot_mpidr = current_mpidr();
+    CORE_MPIDRS[0].store(boot_mpidr, Ordering::Relaxed);
+    let mut next_core = 1;
+
+    for_each_cpu(tree, |mpidr, method| {
+        let mpidr = mpidr & MPIDR_AFFINITY_MASK;
+        if mpidr == boot_mpidr {
+            return;
+        }
+        if next_core == MAX_CORES {
+            println!("core with mpidr {:#x} is beyond MAX_CORES; leaving it off", mpidr);
+            return;
+        }
+        let method = if let Some(method) = method {
+            method
+        } else {
+            println!("core with mpidr {:#x} has no enable method we know", mpidr);
+            return;
+        };
+
+        let core = next_core;
+        let stack = framealloc::alloc_frame(SECONDARY_STACK_SIZE)
+            .expect("couldn't allocate a stack for a secondary core");
+        let stack_top = u64::from(memory::paddr_to_kaddr(stack)) + SECONDARY_STACK_SIZE;
+        SECONDARY_STACKS[core].store(stack_top, Ordering::Relaxed);
+        CORE_MPIDRS[core].store(mpidr, Ordering::Release);
+        // the new core looks itself up with its caches off
+        asm::clean_dcache_range(CORE_MPIDRS.as_ptr() as u64, mem::size_of_val(&CORE_MPIDRS) as u64);
+
+        let online = cores_online();
+        if let Err(e) = release(mpidr, method, core) {
+            println!("couldn't start core {} (mpidr {:#x}): {}", core, mpidr, e);
+            CORE_MPIDRS[core].store(u64::MAX, Ordering::Relaxed);
+            unsafe { framealloc::free_frame(stack, SECONDARY_STACK_SIZE) };
+            return;
+        }
+        next_core += 1;
+
+        if time::block_for(|| cores_online() > online, CHECK_IN_TIMEOUT, Wait::Spin).is_err() {
+            println!("core {} (mpidr {:#x}) never checked in", core, mpidr);
+        }
+    });
+}
+
+fn release(mpidr: u64, method: EnableMethod, core: usize) -> Result<(), psci::PsciError> {
+    // the new core starts with its mmu off
+    let entry = memory::kernel_paddr(boot::_secondary_entry as *const () as u64);
+    match method {
+        EnableMethod::Psci => psci::cpu_on(mpidr, entry, core as u64),
+        EnableMethod::SpinTable { release_addr } => {
+            let release = Paddr::from(release_addr).as_mut::<u64>();
+            unsafe { core::ptr::write_volatile(release, u64::from(entry)) };
+            // the core spinning on it has its caches off
+            asm::clean_dcache_range(release as u64, 8);
+            asm::sev();
+            Ok(())
+        }
+    }
+}
+
+/// where each secondary core lands once it's in el1 with a stack.
+pub fn secondary_main() -> ! {
+    // sgis belong to each core, so `register_handler` only set this one up on the boot core
+    if irq::is_initialized() {
+        irq::set_priority(STOP_SGI, irq::DEFAULT_PRIORITY);
+        irq::enable(STOP_SGI);
+    }
+    CORES_ONLINE.fetch_add(1, Ordering::AcqRel);
+    println!("core {} is up, MPIDR = {:#x}", percpu::this().core_id(), MPIDR_EL1.get());
+    sleep_forever()
+}
@@ -0,0 +1,284 @@
+//! time, as told by the generic timer.
+//!
+//! `Instant` is a reading of the system counter, which never goes backwards and ticks
+//! at the same rate on every core. each core also gets one timer, which can call a
+//! function once or periodically, from irq context.
+
+use crate::driver::timer;
+use crate::irq::{self, Irq};
+use crate::percpu::Counters;
+use crate::smp::MAX_CORES;
+use crate::{asm, board, percpu};
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+use core::time::Duration;
+use spin::Mutex;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// how often `sleep_for` wakes up to check the time, roughly. the event stream can
+/// only do powers of two, so this is rounded to one.
+const EVENT_STREAM_PERIOD: Duration = Duration::from_micros(100);
+
+/// the most `block_until` spins between tries, in nops.
+const MAX_BACKOFF: u32 = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// a moment in time, as a reading of the system counter.
+pub struct Instant(u64);
+
+impl Instant {
+    /// a deadline that never comes. the counter would take centuries to get here.
+    pub const FOREVER: Instant = Instant(u64::MAX);
+
+    pub fn now() -> Self {
+        Instant(timer::counter())
+    }
+
+    /// the raw counter value.
+    pub fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// how long after `earlier` this is, or zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let since_reset = ticks_to_duration(self.0);
+        write!(f, "{}.{:06}", since_reset.as_secs(), since_reset.subsec_micros())
+    }
+}
+
+/// the counter's frequency in hz.
+pub fn frequency() -> u64 {
+    timer::frequency()
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency() as u128;
+    Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+}
+
+/// round up, so that waiting for this many ticks is never too short.
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * frequency() as u128 + NANOS_PER_SEC - 1) / NANOS_PER_SEC;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// a deadline passed before whatever we were waiting for happened.
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out")
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+/// how `block_until` passes the time between tries.
+pub enum Wait {
+    /// spin, starting with a single nop and backing off exponentially. for things
+    /// that should happen any moment, like a device clearing a busy bit.
+    Spin,
+    /// doze in `wfe` until an event or interrupt, or the event stream that `init_cpu`
+    /// sets up. for things that might take a while, and which whoever makes them happen
+    /// will `sev` for or raise an interrupt about.
+    Event,
+}
+
+/// call `func` until it returns true, or until `deadline` passes. `func` always gets
+/// one last try after the deadline, so that a slow wait can't turn into a timeout.
+pub fn block_until<F>(mut func: F, deadline: Instant, wait: Wait) -> Result<(), TimedOut>
+where
+    F: FnMut() -> bool,
+{
+    let mut backoff = 1;
+    loop {
+        let timed_out = Instant::now() >= deadline;
+        if func() {
+            return Ok(());
+        }
+        if timed_out {
+            return Err(TimedOut);
+        }
+        match wait {
+            Wait::Spin => {
+                asm::block(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Wait::Event => asm::wfe(),
+        }
+    }
+}
+
+/// `block_until`, with a deadline `timeout` from now.
+pub fn block_for<F>(func: F, timeout: Duration, wait: Wait) -> Result<(), TimedOut>
+where
+    F: FnMut() -> bool,
+{
+    block_until(func, Instant::now() + timeout, wait)
+}
+
+/// busy-wait until `deadline`, dozing in `wfe` between checks.
+pub fn sleep_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        // the event stream guarantees we wake up again soon, even if nothing else
+        // happens
+        asm::wfe();
+    }
+}
+
+/// busy-wait for at least `duration`, dozing in `wfe` between checks.
+pub fn sleep_for(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+#[derive(Copy, Clone)]
+struct TimerState {
+    deadline: u64,
+    /// in ticks, or `None` for a one-shot timer.
+    period: Option<u64>,
+    callback: Option<fn()>,
+}
+
+impl TimerState {
+    const IDLE: TimerState = TimerState {
+        deadline: 0,
+        period: None,
+        callback: None,
+    };
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const IDLE_TIMER: Mutex<TimerState> = Mutex::new(TimerState::IDLE);
+
+/// each core's timer, indexed by core number. only ever touched by the core it
+/// belongs to, with irqs masked.
+static TIMERS: [Mutex<TimerState>; MAX_CORES] = [IDLE_TIMER; MAX_CORES];
+
+fn with_timer<F: FnOnce(&mut TimerState) -> R, R>(f: F) -> R {
+    irq::without_interrupts(|| f(&mut TIMERS[percpu::this().core_id()].lock()))
+}
+
+/// call `callback` from irq context once `after` has passed, on this core. replaces
+/// any timer already set on this core.
+pub fn set_oneshot(after: Duration, callback: fn()) {
+    with_timer(|timer| {
+        let deadline = Instant::now() + after;
+        *timer = TimerState {
+            deadline: deadline.ticks(),
+            period: None,
+            callback: Some(callback),
+        };
+        timer::set_deadline(deadline.ticks());
+    });
+}
+
+/// call `callback` from irq context every `period`, on this core. replaces any timer
+/// already set on this core.
+pub fn set_periodic(period: Duration, callback: fn()) {
+    let ticks = duration_to_ticks(period).max(1);
+    with_timer(|timer| {
+        let deadline = timer::counter() + ticks;
+        *timer = TimerState {
+            deadline,
+            period: Some(ticks),
+            callback: Some(callback),
+        };
+        timer::set_deadline(deadline);
+    });
+}
+
+/// stop this core's timer.
+pub fn cancel_timer() {
+    with_timer(|timer| {
+        *timer = TimerState::IDLE;
+        timer::stop();
+    });
+}
+
+fn handle_timer(_irq: Irq) {
+    Counters::bump(percpu!(counters.timer_ticks));
+    let callback = with_timer(|timer| {
+        match timer.period {
+            Some(period) => {
+                // if we've fallen more than a period behind, drop the ticks we missed
+                // rather than firing them back to back
+                let now = timer::counter();
+                timer.deadline += period;
+                if timer.deadline <= now {
+                    timer.deadline = now + period;
+                }
+                timer::set_deadline(timer.deadline);
+                timer.callback
+            }
+            None => {
+                timer::stop();
+                timer.callback.take()
+            }
+        }
+    });
+    if let Some(callback) = callback {
+        callback();
+    }
+}
+
+/// check the counter works, and take the timer interrupt if there's an interrupt
+/// controller to take it from. called once, on the boot core, which it also sets up.
+pub fn init() {
+    assert!(frequency() != 0, "the firmware didn't set CNTFRQ_EL0");
+    if irq::is_initialized() {
+        irq::register_handler(board::irq::TIMER_IRQ, handle_timer);
+    }
+    init_cpu();
+}
+
+/// set up the calling core's timer. `init` does this for the boot core; the others
+/// have to call it themselves.
+pub fn init_cpu() {
+    timer::stop();
+
+    // the event stream can't go slower than every 2^16 ticks
+    let period_ticks = duration_to_ticks(EVENT_STREAM_PERIOD).max(2);
+    let bit = (63 - period_ticks.leading_zeros()).saturating_sub(1).min(15);
+    timer::enable_event_stream(bit);
+
+    // the timer interrupt is per-core, so enabling it on the boot core didn't enable it
+    // here
+    if irq::is_initialized() {
+        irq::enable(board::irq::TIMER_IRQ);
+    }
+}